@@ -1,6 +1,12 @@
+use super::eval::{self, Lambda, Value};
+use super::number::Number;
+use super::symbol::Symbol;
 use codespan::*;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub kind: TokenKind,
     span: Span,
@@ -10,23 +16,131 @@ pub struct Token {
 pub enum TokenKind {
     LeftBracket,
     RightBracket,
-    Number(i64),
-    Symbol(String),
+    /// `#(`, which opens a vector literal
+    VectorOpen,
+    Number(Number),
+    Bool(bool),
+    Char(char),
+    Symbol(Symbol),
+    /// `nil`, the empty list, as opposed to the symbol `|nil|`
+    Nil,
+    /// `.` before the tail of a dotted list
+    Dot,
     Str(String),
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
 impl Token {
     pub fn with_span(kind: TokenKind, span: Span) -> Self {
         Token { kind, span }
     }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
 }
 
-#[derive(Debug, PartialEq)]
+/// Forms in tail position are shared, so the evaluator can continue with
+/// them without copying the code of a closure on every call.
+pub type Body = Vec<Rc<Expr>>;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
-    Symbol(Token, String),
-    Str(String),
-    Number(Token, i64),
-    If(Token, Token, Box<Expr>, Box<Expr>, Box<Expr>, Token),
+    Symbol(Token, Symbol),
+    Str(Token, String),
+    Number(Token, Number),
+    Bool(Token, bool),
+    Char(Token, char),
+    Nil(Token, Token),
+    /// `#(datum ...)`, spanning the whole literal
+    Vector(Span, eval::Vector),
+    /// `'datum` or `(quote datum)`, spanning the whole form
+    Quote(Span, Value),
+    /// `` `template `` or `(quasiquote template)`, spanning the whole form
+    Quasiquote(Span, Template),
+    Unquote(Token, Box<Expr>),
+    UnquoteSplicing(Token, Box<Expr>),
+    If(Token, Token, Box<Expr>, Rc<Expr>, Rc<Expr>, Token),
+    Begin(Token, Token, Body, Token),
     Define(Token, Token, Token, Box<Expr>, Token),
-    Call(Token, Token, Vec<Expr>, Token),
+    Lambda(Token, Token, Vec<Token>, Body, Token),
+    Defun(Token, Token, Token, Vec<Token>, Body, Token),
+    Defmacro(Token, Token, Token, Vec<Token>, Body, Token),
+    Let(Token, Token, Vec<(Token, Expr)>, Body, Token),
+    LetStar(Token, Token, Vec<(Token, Expr)>, Body, Token),
+    Letrec(Token, Token, Vec<(Token, Expr)>, Body, Token),
+    Call(Token, Box<Expr>, Vec<Expr>, Token, Expansion),
+}
+
+/// The template of a quasiquote: data, except what is unquoted at its own
+/// level of quasiquotation, which is code.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Template {
+    Datum(Value),
+    Unquote(Box<Expr>),
+    UnquoteSplicing(Box<Expr>),
+    /// the items of a list and its tail, `()` unless the list is dotted
+    List(Vec<Template>, Box<Template>),
+    Vector(Vec<Template>),
+}
+
+/// The expansion of a macro call, kept on the call so the macro runs once
+/// per call site rather than on every evaluation. It belongs to the macro it
+/// came from: a call that reaches another macro, e.g. after a redefinition,
+/// expands again.
+#[derive(Default, Clone)]
+pub struct Expansion(RefCell<Option<(Rc<Lambda>, Rc<Expr>)>>);
+
+impl Expansion {
+    pub fn get(&self, macro_: &Rc<Lambda>) -> Option<Rc<Expr>> {
+        match &*self.0.borrow() {
+            Some((lambda, expr)) if Rc::ptr_eq(lambda, macro_) => Some(expr.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, macro_: Rc<Lambda>, expr: Rc<Expr>) {
+        *self.0.borrow_mut() = Some((macro_, expr));
+    }
+}
+
+/// Calls are the same code whether or not they have been expanded yet.
+impl PartialEq for Expansion {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Expansion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Expansion")
+    }
+}
+
+impl Expr {
+    /// Span covering the whole expression, from its first to its last token.
+    pub fn span(&self) -> Span {
+        use Expr::*;
+        match self {
+            Symbol(tok, _) | Str(tok, _) | Number(tok, _) | Bool(tok, _) | Char(tok, _) => {
+                tok.span()
+            }
+            Quote(span, _) | Quasiquote(span, _) | Vector(span, _) => *span,
+            Unquote(tok, expr) | UnquoteSplicing(tok, expr) => tok.span().merge(expr.span()),
+            Nil(open, close)
+            | If(open, .., close)
+            | Begin(open, .., close)
+            | Define(open, .., close)
+            | Lambda(open, .., close)
+            | Defun(open, .., close)
+            | Defmacro(open, .., close)
+            | Let(open, .., close)
+            | LetStar(open, .., close)
+            | Letrec(open, .., close)
+            | Call(open, _, _, close, _) => open.span().merge(close.span()),
+        }
+    }
 }
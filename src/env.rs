@@ -1,270 +1,1384 @@
 use super::eval::*;
-use big_s::S;
+use super::gc;
+use super::number::{self, Number};
+use super::print;
+use super::symbol::Symbol;
 use itertools::Itertools;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
 
-pub fn make_global_env() -> HashMap<String, Value> {
-    let mut env = HashMap::new();
+/// Longest vector `make-vector` makes, 4GB of values: an allocation that
+/// size can succeed on a system that overcommits memory, only for the
+/// process to be killed as `make-vector` fills it in.
+const MAX_VECTOR_LENGTH: usize = 1 << 26;
 
-    env.insert(
-        S("print"),
-        Value::Callable(|values| {
+/// A frame in the lexical environment chain (global -> function -> let).
+#[derive(Clone)]
+pub struct Env(Rc<RefCell<Frame>>);
+
+/// Where `print` and friends write.
+pub type Output = Rc<RefCell<dyn Write>>;
+
+/// Where `read-line` reads from.
+pub type Input = Rc<RefCell<dyn BufRead>>;
+
+struct Frame {
+    vars: HashMap<Symbol, Value>,
+    parent: Option<Env>,
+    /// set on the global frame only; stdin if unset
+    input: Option<Input>,
+    /// set on the global frame only; stdout if unset
+    output: Option<Output>,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        Env::with_frame(Frame {
+            vars: HashMap::new(),
+            parent: None,
+            input: None,
+            output: None,
+        })
+    }
+
+    pub fn with_parent(parent: &Env) -> Self {
+        Env::with_frame(Frame {
+            vars: HashMap::new(),
+            parent: Some(parent.clone()),
+            input: None,
+            output: None,
+        })
+    }
+
+    fn with_frame(frame: Frame) -> Self {
+        let frame = Rc::new(RefCell::new(frame));
+        gc::register(&frame);
+        Env(frame)
+    }
+
+    pub(crate) fn addr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    /// Look `name` up, walking from this frame towards the global one.
+    pub fn get(&self, name: Symbol) -> Option<Value> {
+        let frame = self.0.borrow();
+        match frame.vars.get(&name) {
+            Some(value) => Some(value.clone()),
+            None => frame.parent.as_ref().and_then(|parent| parent.get(name)),
+        }
+    }
+
+    /// Bind `name` in this frame, shadowing any outer binding.
+    pub fn define(&self, name: Symbol, value: Value) {
+        self.0.borrow_mut().vars.insert(name, value);
+    }
+
+    /// Update the nearest binding of `name`, or define it globally if unbound.
+    pub fn set(&self, name: Symbol, value: Value) {
+        self.lookup_frame(name)
+            .unwrap_or_else(|| self.global())
+            .define(name, value)
+    }
+
+    fn lookup_frame(&self, name: Symbol) -> Option<Env> {
+        let frame = self.0.borrow();
+        if frame.vars.contains_key(&name) {
+            Some(self.clone())
+        } else {
+            frame
+                .parent
+                .as_ref()
+                .and_then(|parent| parent.lookup_frame(name))
+        }
+    }
+
+    /// The output of the global environment.
+    pub fn output(&self) -> Output {
+        let global = self.global();
+        let output = global.0.borrow().output.clone();
+        output.unwrap_or_else(|| Rc::new(RefCell::new(io::stdout())))
+    }
+
+    /// Send the output of the global environment to `output`, and return
+    /// the previous one.
+    pub fn set_output(&self, output: Output) -> Output {
+        let previous = self.output();
+        self.global().0.borrow_mut().output = Some(output);
+        previous
+    }
+
+    /// The input of the global environment.
+    pub fn input(&self) -> Input {
+        let global = self.global();
+        let input = global.0.borrow().input.clone();
+        input.unwrap_or_else(|| Rc::new(RefCell::new(io::stdin().lock())))
+    }
+
+    /// Read the input of the global environment from `input`.
+    pub fn set_input(&self, input: Input) {
+        self.global().0.borrow_mut().input = Some(input);
+    }
+
+    fn global(&self) -> Env {
+        match &self.0.borrow().parent {
+            Some(parent) => parent.global(),
+            None => self.clone(),
+        }
+    }
+}
+
+impl gc::Trace for RefCell<Frame> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(frame) = self.try_borrow() {
+            frame
+                .vars
+                .values()
+                .for_each(|value| gc::trace_value(value, visit));
+            if let Some(parent) = &frame.parent {
+                visit(parent.addr());
+            }
+        }
+    }
+
+    fn clear(&self) {
+        let vars = std::mem::take(&mut self.borrow_mut().vars);
+        let parent = self.borrow_mut().parent.take();
+        drop((vars, parent));
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::new()
+    }
+}
+
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = self
+            .0
+            .borrow()
+            .vars
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        f.debug_tuple("Env").field(&names).finish()
+    }
+}
+
+pub fn make_global_env() -> Env {
+    let env = Env::new();
+
+    env.define(
+        Symbol::intern("print"),
+        Value::Callable(|ctx, values| {
             for value in values.iter() {
-                println!("{}", value);
+                emit(ctx, "print", format_args!("{}\n", value))?;
             }
             Ok(last_or_nil(values))
         }),
     );
 
-    env.insert(
-        S("exit"),
-        Value::Callable(|values| {
-            let code = values.into_iter().last().unwrap_or(Value::Number(0));
-            std::process::exit(code.into_num() as i32)
+    env.define(
+        Symbol::intern("write"),
+        Value::Callable(|ctx, values| {
+            for value in values.iter() {
+                emit(ctx, "write", format_args!("{}", print::write(value)))?;
+            }
+            Ok(last_or_nil(values))
         }),
     );
 
-    env.insert(
-        S("begin"),
-        Value::Callable(|values| Ok(last_or_nil(values))),
+    env.define(
+        Symbol::intern("display"),
+        Value::Callable(|ctx, values| {
+            for value in values.iter() {
+                emit(ctx, "display", format_args!("{}", print::display(value)))?;
+            }
+            Ok(last_or_nil(values))
+        }),
     );
 
-    env.insert(
-        S("+"),
-        Value::Callable(|values| {
-            Ok(Value::Number(
-                values.iter().map(|n| n.clone().into_num()).sum(),
-            ))
+    env.define(
+        Symbol::intern("newline"),
+        Value::Callable(|ctx, values| match values.len() {
+            0 => {
+                emit(ctx, "newline", format_args!("\n"))?;
+                Ok(Value::Nil)
+            }
+            n => Err(EvalError::arity("newline", "0", n)),
         }),
     );
 
-    env.insert(
-        S("*"),
-        Value::Callable(|values| {
-            Ok(Value::Number(
-                values.iter().map(|n| n.clone().into_num()).product(),
-            ))
+    env.define(
+        Symbol::intern("current-output-port"),
+        Value::Callable(|ctx, values| match values.len() {
+            0 => Ok(Value::Port(Port::Output(ctx.env().output()))),
+            n => Err(EvalError::arity("current-output-port", "0", n)),
         }),
     );
 
-    env.insert(
-        S("-"),
-        Value::Callable(|values| {
-            Ok(Value::Number(
-                if let Some((first, rest)) = values.split_first() {
-                    let first = first.clone().into_num();
-                    if rest.is_empty() {
-                        -first
-                    } else {
-                        rest.iter().fold(first, |n, m| n - m.clone().into_num())
-                    }
-                } else {
-                    0
-                },
-            ))
+    env.define(
+        Symbol::intern("current-input-port"),
+        Value::Callable(|ctx, values| match values.len() {
+            0 => Ok(Value::Port(Port::Input(ctx.env().input()))),
+            n => Err(EvalError::arity("current-input-port", "0", n)),
         }),
     );
 
-    env.insert(
-        S("/"),
-        Value::Callable(|values| {
-            if let Some((first, rest)) = values.split_first() {
-                let first = first.clone().into_num();
-                Ok(Value::Number(if rest.is_empty() {
-                    1 / first
-                } else {
-                    rest.iter().fold(first, |n, m| n / m.clone().into_num())
-                }))
-            } else {
-                Err(EvalError(S("Wrong number of arguments: /, 0")))
+    env.define(
+        Symbol::intern("with-output-to-string"),
+        Value::Callable(|ctx, values| {
+            let thunk = one("with-output-to-string", values)?;
+            let buffer = Rc::new(RefCell::new(Vec::new()));
+            let previous = ctx.env().set_output(buffer.clone());
+            let result = ctx.call(&thunk, Vec::new());
+            ctx.env().set_output(previous);
+            result?;
+            let output = std::mem::take(&mut *buffer.borrow_mut());
+            Ok(Value::Str(String::from_utf8_lossy(&output).into_owned()))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("write-string"),
+        Value::Callable(|ctx, values| {
+            let output = match &values[..] {
+                [_] => ctx.env().output(),
+                [_, Value::Port(Port::Output(output))] => output.clone(),
+                [_, other] => {
+                    return Err(EvalError::wrong_type(
+                        "write-string",
+                        "output port",
+                        other,
+                        2,
+                    ))
+                }
+                _ => return Err(EvalError::arity("write-string", "1 or 2", values.len())),
+            };
+            let s = str_arg("write-string", &values[0], 1)?;
+            output
+                .borrow_mut()
+                .write_all(s.as_bytes())
+                .map_err(|e| EvalError::new(format!("write-string: {}", e)))?;
+            Ok(values[0].clone())
+        }),
+    );
+
+    env.define(
+        Symbol::intern("read-line"),
+        Value::Callable(|ctx, values| {
+            let input = match &values[..] {
+                [] => ctx.env().input(),
+                [Value::Port(Port::Input(input))] => input.clone(),
+                [other] => return Err(EvalError::wrong_type("read-line", "input port", other, 1)),
+                _ => return Err(EvalError::arity("read-line", "0 or 1", values.len())),
+            };
+            let mut line = String::new();
+            let read = input
+                .borrow_mut()
+                .read_line(&mut line)
+                .map_err(|e| EvalError::new(format!("read-line: {}", e)))?;
+            if read == 0 {
+                return Ok(Value::Nil);
+            }
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
             }
+            Ok(Value::Str(line))
         }),
     );
 
-    env.insert(
-        S("="),
-        Value::Callable(|values| {
-            let first = values.first().unwrap().clone().into_num();
-            Ok(if values.iter().any(|x| x.clone().into_num() != first) {
-                Value::Nil
-            } else {
-                Value::Number(1)
-            })
+    env.define(
+        Symbol::intern("exit"),
+        Value::Callable(|_, values| {
+            let position = values.len();
+            let code = values.into_iter().last().unwrap_or_else(|| Value::int(0));
+            let code = code.into_int("exit", position.max(1))?;
+            Err(ErrorKind::Exit(code as i32).into())
         }),
     );
 
-    env.insert(S("eq"), env["="].clone());
+    env.define(
+        Symbol::intern("+"),
+        Value::Callable(|_, values| {
+            let sum = nums("+", &values)?
+                .iter()
+                .fold(Number::Int(0), |n, m| n.add(m));
+            Ok(Value::Number(sum))
+        }),
+    );
 
-    env.insert(
-        S("!"),
-        Value::Callable(|values| match values.len() {
-            1 => Ok(if values.first().unwrap().is_truthy() {
-                Value::Nil
-            } else {
-                Value::Number(1)
-            }),
-            n if n > 1 => Err(EvalError(S("too many arguments given to NOT"))),
-            _ => Err(EvalError(S("too few arguments givien to NOT"))),
+    env.define(
+        Symbol::intern("*"),
+        Value::Callable(|_, values| {
+            let product = nums("*", &values)?
+                .iter()
+                .fold(Number::Int(1), |n, m| n.mul(m));
+            Ok(Value::Number(product))
         }),
     );
 
-    env.insert(S("not"), env["!"].clone());
+    env.define(
+        Symbol::intern("-"),
+        Value::Callable(|_, values| {
+            let nums = nums("-", &values)?;
+            Ok(Value::Number(match nums.split_first() {
+                Some((first, [])) => first.neg(),
+                Some((first, rest)) => rest.iter().fold(first.clone(), |n, m| n.sub(m)),
+                None => Number::Int(0),
+            }))
+        }),
+    );
 
-    env.insert(
-        S("<"),
-        Value::Callable(|values| {
-            let vs = Values::from(values);
-            Ok(if let Some(v) = vs.to_tuples() {
-                if v.iter()
-                    .filter(|(a, b)| !(a.clone().into_num() < b.clone().into_num()))
-                    .collect::<Vec<_>>()
-                    .is_empty()
-                {
-                    Value::Number(1)
-                } else {
-                    Value::Nil
+    env.define(
+        Symbol::intern("/"),
+        Value::Callable(|_, values| {
+            let nums = nums("/", &values)?;
+            match nums.split_first() {
+                Some((first, [])) => divide(&Number::Int(1), first),
+                Some((first, rest)) => rest.iter().try_fold(first.clone(), |n, m| divide(&n, m)),
+                None => Err(EvalError::arity("/", "at least 1", 0)),
+            }
+            .map(Value::Number)
+        }),
+    );
+
+    env.define(
+        Symbol::intern("="),
+        Value::Callable(|_, values| compare("=", values, Ordering::is_eq)),
+    );
+
+    env.define(Symbol::intern("eq"), env.get(Symbol::intern("=")).unwrap());
+
+    env.define(
+        Symbol::intern("!"),
+        Value::Callable(|_, values| match values.len() {
+            1 => Ok(Value::Bool(!values[0].is_truthy())),
+            n => Err(EvalError::arity("not", "1", n)),
+        }),
+    );
+
+    env.define(Symbol::intern("not"), env.get(Symbol::intern("!")).unwrap());
+
+    env.define(
+        Symbol::intern("<"),
+        Value::Callable(|_, values| compare("<", values, Ordering::is_lt)),
+    );
+
+    env.define(
+        Symbol::intern(">"),
+        Value::Callable(|_, values| compare(">", values, Ordering::is_gt)),
+    );
+
+    env.define(
+        Symbol::intern("<="),
+        Value::Callable(|_, values| compare("<=", values, Ordering::is_le)),
+    );
+
+    env.define(
+        Symbol::intern(">="),
+        Value::Callable(|_, values| compare(">=", values, Ordering::is_ge)),
+    );
+
+    env.define(
+        Symbol::intern("mod"),
+        Value::Callable(|_, values| binary("mod", values, Number::modulo)),
+    );
+
+    env.define(
+        Symbol::intern("rem"),
+        Value::Callable(|_, values| binary("rem", values, Number::rem)),
+    );
+
+    env.define(
+        Symbol::intern("expt"),
+        Value::Callable(|_, values| {
+            if let [Value::Number(base), Value::Number(power)] = &values[..] {
+                if base.expt_too_large(power) {
+                    return Err(EvalError::new(format!(
+                        "expt: result would exceed {} bits",
+                        number::MAX_EXPT_BITS
+                    )));
                 }
-            } else {
-                Value::Nil
-            })
-        }),
-    );
-
-    env.insert(
-        S(">"),
-        Value::Callable(|values| {
-            let vs = Values::from(values);
-            Ok(if let Some(v) = vs.to_tuples() {
-                if v.iter()
-                    .filter(|(a, b)| !(a.clone().into_num() > b.clone().into_num()))
-                    .collect::<Vec<_>>()
-                    .is_empty()
-                {
-                    Value::Number(1)
-                } else {
-                    Value::Nil
+            }
+            binary("expt", values, Number::expt)
+        }),
+    );
+
+    env.define(
+        Symbol::intern("abs"),
+        Value::Callable(|_, values| unary("abs", values, Number::abs)),
+    );
+
+    env.define(
+        Symbol::intern("sqrt"),
+        Value::Callable(|_, values| unary("sqrt", values, Number::sqrt)),
+    );
+
+    env.define(
+        Symbol::intern("floor"),
+        Value::Callable(|_, values| unary("floor", values, Number::floor)),
+    );
+
+    env.define(
+        Symbol::intern("ceiling"),
+        Value::Callable(|_, values| unary("ceiling", values, Number::ceiling)),
+    );
+
+    env.define(
+        Symbol::intern("round"),
+        Value::Callable(|_, values| unary("round", values, Number::round)),
+    );
+
+    env.define(
+        Symbol::intern("truncate"),
+        Value::Callable(|_, values| unary("truncate", values, Number::truncate)),
+    );
+
+    env.define(
+        Symbol::intern("min"),
+        Value::Callable(|_, values| extremum("min", values, Ordering::Less)),
+    );
+
+    env.define(
+        Symbol::intern("max"),
+        Value::Callable(|_, values| extremum("max", values, Ordering::Greater)),
+    );
+
+    env.define(
+        Symbol::intern("sin"),
+        Value::Callable(|_, values| float("sin", values, f64::sin)),
+    );
+
+    env.define(
+        Symbol::intern("cos"),
+        Value::Callable(|_, values| float("cos", values, f64::cos)),
+    );
+
+    env.define(
+        Symbol::intern("tan"),
+        Value::Callable(|_, values| float("tan", values, f64::tan)),
+    );
+
+    env.define(
+        Symbol::intern("asin"),
+        Value::Callable(|_, values| float("asin", values, f64::asin)),
+    );
+
+    env.define(
+        Symbol::intern("acos"),
+        Value::Callable(|_, values| float("acos", values, f64::acos)),
+    );
+
+    env.define(
+        Symbol::intern("atan"),
+        Value::Callable(|_, values| match values.len() {
+            2 => {
+                let nums = nums("atan", &values)?;
+                Ok(Value::Number(Number::Float(
+                    nums[0].to_f64().atan2(nums[1].to_f64()),
+                )))
+            }
+            1 => float("atan", values, f64::atan),
+            n => Err(EvalError::arity("atan", "1 or 2", n)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("exp"),
+        Value::Callable(|_, values| float("exp", values, f64::exp)),
+    );
+
+    env.define(
+        Symbol::intern("log"),
+        Value::Callable(|_, values| match values.len() {
+            2 => {
+                let nums = nums("log", &values)?;
+                Ok(Value::Number(Number::Float(
+                    nums[0].to_f64().ln() / nums[1].to_f64().ln(),
+                )))
+            }
+            1 => float("log", values, f64::ln),
+            n => Err(EvalError::arity("log", "1 or 2", n)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("list"),
+        Value::Callable(|_, values| Ok(values.into_iter().collect())),
+    );
+
+    env.define(
+        Symbol::intern("cons"),
+        Value::Callable(|_, values| match values.iter().collect_tuple() {
+            Some((a, b)) => Ok(Value::Cons(Cons::new(a.clone(), b.clone()))),
+            None => Err(EvalError::arity("cons", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("car"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Cons(cons)] => Ok(cons.car()),
+            [other] => Err(EvalError::wrong_type("car", "cons", other, 1)),
+            _ => Err(EvalError::arity("car", "1", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("cdr"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Cons(cons)] => Ok(cons.cdr()),
+            [other] => Err(EvalError::wrong_type("cdr", "cons", other, 1)),
+            _ => Err(EvalError::arity("cdr", "1", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("set-car!"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Cons(cons), value] => {
+                cons.set_car(value.clone());
+                Ok(value.clone())
+            }
+            [other, _] => Err(EvalError::wrong_type("set-car!", "cons", other, 1)),
+            _ => Err(EvalError::arity("set-car!", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("set-cdr!"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Cons(cons), value] => {
+                cons.set_cdr(value.clone());
+                Ok(value.clone())
+            }
+            [other, _] => Err(EvalError::wrong_type("set-cdr!", "cons", other, 1)),
+            _ => Err(EvalError::arity("set-cdr!", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("eq?"),
+        Value::Callable(|_, values| match &values[..] {
+            [a, b] => Ok(Value::Bool(a.is_eq(b))),
+            _ => Err(EvalError::arity("eq?", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("equal?"),
+        Value::Callable(|_, values| match &values[..] {
+            [a, b] => Ok(Value::Bool(a == b)),
+            _ => Err(EvalError::arity("equal?", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("append"),
+        Value::Callable(|_, mut values| {
+            let tail = values.pop().unwrap_or(Value::Nil);
+            let mut items = Vec::new();
+            for (i, value) in values.into_iter().enumerate() {
+                items.extend(list_to_vec(value, "append", i + 1)?);
+            }
+            Ok(list(items, tail))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("reverse"),
+        Value::Callable(|_, values| {
+            let items = list_to_vec(one("reverse", values)?, "reverse", 1)?;
+            Ok(items.into_iter().rev().collect())
+        }),
+    );
+
+    env.define(
+        Symbol::intern("length"),
+        Value::Callable(|_, values| {
+            let items = list_to_vec(one("length", values)?, "length", 1)?;
+            Ok(Value::int(items.len() as i64))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("nth"),
+        Value::Callable(|_, values| match values.iter().collect_tuple() {
+            Some((n, list)) => {
+                let n = n.clone().into_int("nth", 1)?;
+                if n < 0 {
+                    return Err(EvalError::new(format!("nth: negative index {}", n)));
                 }
-            } else {
-                Value::Nil
-            })
-        }),
-    );
-
-    env.insert(
-        S("<="),
-        Value::Callable(|values| {
-            let vs = Values::from(values);
-            Ok(if let Some(v) = vs.to_tuples() {
-                if v.iter()
-                    .filter(|(a, b)| !(a.clone().into_num() <= b.clone().into_num()))
-                    .collect::<Vec<_>>()
-                    .is_empty()
-                {
-                    Value::Number(1)
-                } else {
-                    Value::Nil
+                let items = list_to_vec(list.clone(), "nth", 2)?;
+                Ok(items.into_iter().nth(n as usize).unwrap_or(Value::Nil))
+            }
+            None => Err(EvalError::arity("nth", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("last"),
+        Value::Callable(|_, values| {
+            let items = list_to_vec(one("last", values)?, "last", 1)?;
+            Ok(last_or_nil(items))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("apply"),
+        Value::Callable(|ctx, mut values| {
+            if values.len() < 2 {
+                return Err(EvalError::arity("apply", "at least 2", values.len()));
+            }
+            let position = values.len();
+            let last = values.pop().unwrap();
+            let func = values.remove(0);
+            values.extend(list_to_vec(last, "apply", position)?);
+            ctx.call(&func, values)
+        }),
+    );
+
+    env.define(
+        Symbol::intern("map"),
+        Value::Callable(|ctx, values| {
+            let (func, lists) = func_and_lists("map", values)?;
+            zip_lists(lists)
+                .into_iter()
+                .map(|args| ctx.call(&func, args))
+                .collect()
+        }),
+    );
+
+    env.define(
+        Symbol::intern("for-each"),
+        Value::Callable(|ctx, values| {
+            let (func, lists) = func_and_lists("for-each", values)?;
+            for args in zip_lists(lists) {
+                ctx.call(&func, args)?;
+            }
+            Ok(Value::Nil)
+        }),
+    );
+
+    env.define(
+        Symbol::intern("filter"),
+        Value::Callable(|ctx, values| match &values[..] {
+            [pred, items] => {
+                let mut kept = Vec::new();
+                for item in list_to_vec(items.clone(), "filter", 2)? {
+                    if ctx.call(pred, vec![item.clone()])?.is_truthy() {
+                        kept.push(item);
+                    }
                 }
-            } else {
-                Value::Nil
-            })
-        }),
-    );
-
-    env.insert(
-        S(">="),
-        Value::Callable(|values| {
-            let vs = Values::from(values);
-            Ok(if let Some(v) = vs.to_tuples() {
-                if v.iter()
-                    .filter(|(a, b)| !(a.clone().into_num() >= b.clone().into_num()))
-                    .collect::<Vec<_>>()
-                    .is_empty()
-                {
-                    Value::Number(1)
-                } else {
-                    Value::Nil
+                Ok(kept.into_iter().collect())
+            }
+            _ => Err(EvalError::arity("filter", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("fold-left"),
+        Value::Callable(|ctx, values| match &values[..] {
+            [func, init, items] => list_to_vec(items.clone(), "fold-left", 3)?
+                .into_iter()
+                .try_fold(init.clone(), |acc, item| ctx.call(func, vec![acc, item])),
+            _ => Err(EvalError::arity("fold-left", "3", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("fold-right"),
+        Value::Callable(|ctx, values| match &values[..] {
+            [func, init, items] => list_to_vec(items.clone(), "fold-right", 3)?
+                .into_iter()
+                .rev()
+                .try_fold(init.clone(), |acc, item| ctx.call(func, vec![item, acc])),
+            _ => Err(EvalError::arity("fold-right", "3", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("reduce"),
+        Value::Callable(|ctx, values| match &values[..] {
+            [func, default, items] => {
+                let mut items = list_to_vec(items.clone(), "reduce", 3)?.into_iter();
+                match items.next() {
+                    Some(first) => {
+                        items.try_fold(first, |acc, item| ctx.call(func, vec![acc, item]))
+                    }
+                    None => Ok(default.clone()),
                 }
-            } else {
-                Value::Nil
-            })
+            }
+            _ => Err(EvalError::arity("reduce", "3", values.len())),
         }),
     );
 
-    env.insert(
-        S("list"),
-        Value::Callable(|values| {
-            if values.len() > 1 {
-                let (first, rest) = values.split_first().unwrap();
-                let mut ret = Cons::new(first.clone(), Value::Nil);
-                for value in rest {
-                    ret.append(value.clone());
+    env.define(
+        Symbol::intern("sort"),
+        Value::Callable(|ctx, values| match &values[..] {
+            [items, less] => {
+                let mut items = list_to_vec(items.clone(), "sort", 1)?;
+                let mut error = None;
+                let mut less = |a: &Value, b: &Value| {
+                    if error.is_some() {
+                        return false;
+                    }
+                    match ctx.call(less, vec![a.clone(), b.clone()]) {
+                        Ok(value) => value.is_truthy(),
+                        Err(e) => {
+                            error = Some(e);
+                            false
+                        }
+                    }
+                };
+                // stable, and only asks `less` questions
+                items.sort_by(|a, b| {
+                    if less(a, b) {
+                        Ordering::Less
+                    } else if less(b, a) {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Equal
+                    }
+                });
+                match error {
+                    Some(e) => Err(e),
+                    None => Ok(items.into_iter().collect()),
                 }
-                Ok(Value::Cons(ret))
-            } else {
-                Ok(Value::Cons(Cons::new(
-                    values.first().unwrap().clone(),
-                    Value::Nil,
-                )))
             }
+            _ => Err(EvalError::arity("sort", "2", values.len())),
         }),
     );
 
-    env.insert(
-        S("cons"),
-        Value::Callable(|values| {
-            if let Some((a, b)) = values.iter().next_tuple() {
-                Ok(Value::Cons(Cons::new(a.clone(), b.clone())))
-            } else {
-                Ok(Value::Nil)
+    env.define(
+        Symbol::intern("string-length"),
+        Value::Callable(|_, values| {
+            let s = one("string-length", values)?;
+            Ok(Value::int(
+                str_arg("string-length", &s, 1)?.chars().count() as i64
+            ))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("substring"),
+        Value::Callable(|_, values| {
+            if !(2..=3).contains(&values.len()) {
+                return Err(EvalError::arity("substring", "2 or 3", values.len()));
+            }
+            let chars = str_arg("substring", &values[0], 1)?
+                .chars()
+                .collect::<Vec<_>>();
+            let start = values[1].clone().into_int("substring", 2)?;
+            let end = match values.get(2) {
+                Some(end) => end.clone().into_int("substring", 3)?,
+                None => chars.len() as i64,
+            };
+            if start < 0 || end < start || end > chars.len() as i64 {
+                return Err(EvalError::new(format!(
+                    "substring: {}..{} is out of range for a string of length {}",
+                    start,
+                    end,
+                    chars.len()
+                )));
+            }
+            Ok(Value::Str(
+                chars[start as usize..end as usize].iter().collect(),
+            ))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string-append"),
+        Value::Callable(|_, values| Ok(Value::Str(strs("string-append", &values)?.concat()))),
+    );
+
+    env.define(
+        Symbol::intern("string-split"),
+        Value::Callable(|_, values| {
+            let strs = strs("string-split", &values)?;
+            let parts = match strs[..] {
+                [s] => s.split_whitespace().collect::<Vec<_>>(),
+                [_, ""] => return Err(EvalError::new("string-split: empty separator")),
+                [s, separator] => s.split(separator).collect(),
+                _ => return Err(EvalError::arity("string-split", "1 or 2", values.len())),
+            };
+            Ok(parts.into_iter().map(|s| Value::Str(s.into())).collect())
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string-join"),
+        Value::Callable(|_, values| {
+            let separator = match &values[..] {
+                [_] => " ",
+                [_, separator] => str_arg("string-join", separator, 2)?,
+                _ => return Err(EvalError::arity("string-join", "1 or 2", values.len())),
+            };
+            let items = list_to_vec(values[0].clone(), "string-join", 1)?;
+            Ok(Value::Str(strs("string-join", &items)?.join(separator)))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string-upcase"),
+        Value::Callable(|_, values| {
+            let s = one("string-upcase", values)?;
+            Ok(Value::Str(str_arg("string-upcase", &s, 1)?.to_uppercase()))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string-downcase"),
+        Value::Callable(|_, values| {
+            let s = one("string-downcase", values)?;
+            Ok(Value::Str(
+                str_arg("string-downcase", &s, 1)?.to_lowercase(),
+            ))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string->number"),
+        Value::Callable(|_, values| {
+            let s = one("string->number", values)?;
+            Ok(number::parse(str_arg("string->number", &s, 1)?)
+                .map_or(Value::Bool(false), Value::Number))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("number->string"),
+        Value::Callable(|_, values| {
+            let radix = match values.len() {
+                1 => 10,
+                2 => values[1].clone().into_int("number->string", 2)?,
+                n => return Err(EvalError::arity("number->string", "1 or 2", n)),
+            };
+            let n = values[0].clone().into_num("number->string", 1)?;
+            match n.to_string_radix(radix as u32) {
+                Some(s) if (2..=36).contains(&radix) => Ok(Value::Str(s)),
+                _ => Err(EvalError::new(format!(
+                    "number->string: can't write {} in radix {}",
+                    n, radix
+                ))),
             }
         }),
     );
 
-    env.insert(
-        S("car"),
-        Value::Callable(|values| match values.first() {
-            Some(Value::Cons(cons)) => Ok(cons.clone().car()),
-            _ => Err(EvalError(S("Wrong argument type: car require cons"))),
+    env.define(
+        Symbol::intern("string=?"),
+        Value::Callable(|_, values| compare_strs("string=?", values, Ordering::is_eq)),
+    );
+
+    env.define(
+        Symbol::intern("string<?"),
+        Value::Callable(|_, values| compare_strs("string<?", values, Ordering::is_lt)),
+    );
+
+    env.define(
+        Symbol::intern("string>?"),
+        Value::Callable(|_, values| compare_strs("string>?", values, Ordering::is_gt)),
+    );
+
+    env.define(
+        Symbol::intern("format"),
+        Value::Callable(|_, values| format(values)),
+    );
+
+    env.define(
+        Symbol::intern("string-ref"),
+        Value::Callable(|_, values| {
+            let (s, k) = match &values[..] {
+                [s, k] => (s, k),
+                _ => return Err(EvalError::arity("string-ref", "2", values.len())),
+            };
+            let s = str_arg("string-ref", s, 1)?;
+            let k = k.clone().into_int("string-ref", 2)?;
+            usize::try_from(k)
+                .ok()
+                .and_then(|k| s.chars().nth(k))
+                .map(Value::Char)
+                .ok_or_else(|| {
+                    EvalError::new(format!(
+                        "string-ref: index {} is out of range for a string of length {}",
+                        k,
+                        s.chars().count()
+                    ))
+                })
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string->list"),
+        Value::Callable(|_, values| {
+            let s = one("string->list", values)?;
+            Ok(str_arg("string->list", &s, 1)?
+                .chars()
+                .map(Value::Char)
+                .collect())
         }),
     );
 
-    env.insert(
-        S("cdr"),
-        Value::Callable(|values| match values.first() {
-            Some(Value::Cons(cons)) => Ok(cons.clone().cdr()),
-            _ => Err(EvalError(S("Wrong argument type: car require cons"))),
+    env.define(
+        Symbol::intern("list->string"),
+        Value::Callable(|_, values| {
+            let list = one("list->string", values)?;
+            list_to_vec(list, "list->string", 1)?
+                .into_iter()
+                .map(|c| match c {
+                    Value::Char(c) => Ok(c),
+                    other => Err(EvalError::wrong_type(
+                        "list->string",
+                        "list of chars",
+                        &other,
+                        1,
+                    )),
+                })
+                .collect::<Result<String, _>>()
+                .map(Value::Str)
         }),
     );
 
-    env.insert(S("T"), Value::Number(1));
+    env.define(
+        Symbol::intern("char->integer"),
+        Value::Callable(|_, values| match one("char->integer", values)? {
+            Value::Char(c) => Ok(Value::int(c as i64)),
+            other => Err(EvalError::wrong_type("char->integer", "char", &other, 1)),
+        }),
+    );
 
-    env.insert(S("t"), Value::Number(1));
+    env.define(
+        Symbol::intern("integer->char"),
+        Value::Callable(|_, values| {
+            let n = one("integer->char", values)?.into_int("integer->char", 1)?;
+            u32::try_from(n)
+                .ok()
+                .and_then(std::char::from_u32)
+                .map(Value::Char)
+                .ok_or_else(|| EvalError::new(format!("integer->char: {} is not a character", n)))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("char-alphabetic?"),
+        Value::Callable(|_, values| match one("char-alphabetic?", values)? {
+            Value::Char(c) => Ok(Value::Bool(c.is_alphabetic())),
+            other => Err(EvalError::wrong_type("char-alphabetic?", "char", &other, 1)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("symbol->string"),
+        Value::Callable(|_, values| match one("symbol->string", values)? {
+            Value::Symbol(s) => Ok(Value::Str(s.as_str().into())),
+            other => Err(EvalError::wrong_type("symbol->string", "symbol", &other, 1)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("string->symbol"),
+        Value::Callable(|_, values| {
+            let s = one("string->symbol", values)?;
+            Ok(Value::Symbol(str_arg("string->symbol", &s, 1)?.into()))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("gensym"),
+        Value::Callable(|_, values| match values.len() {
+            0 => Ok(Value::Symbol(Symbol::gensym())),
+            n => Err(EvalError::arity("gensym", "0", n)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("macroexpand-1"),
+        Value::Callable(|ctx, values| {
+            let form = one("macroexpand-1", values)?;
+            Ok(macroexpand_1(&form, ctx.env())?.unwrap_or(form))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("macroexpand"),
+        Value::Callable(|ctx, values| {
+            let mut form = one("macroexpand", values)?;
+            while let Some(expansion) = macroexpand_1(&form, ctx.env())? {
+                form = expansion;
+            }
+            Ok(form)
+        }),
+    );
+
+    env.define(
+        Symbol::intern("vector"),
+        Value::Callable(|_, values| Ok(Value::Vector(Vector::new(values)))),
+    );
+
+    // `(make-vector n [fill])`: a vector of `n` copies of `fill`, or `nil`.
+    // `n` is at most `MAX_VECTOR_LENGTH`, and an allocation that fails below
+    // that is an error too.
+    env.define(
+        Symbol::intern("make-vector"),
+        Value::Callable(|_, values| {
+            let fill = match values.len() {
+                1 => Value::Nil,
+                2 => values[1].clone(),
+                n => return Err(EvalError::arity("make-vector", "1 or 2", n)),
+            };
+            let n = values[0].clone().into_int("make-vector", 1)?;
+            let n = usize::try_from(n)
+                .map_err(|_| EvalError::new(format!("make-vector: negative length {}", n)))?;
+            if n > MAX_VECTOR_LENGTH {
+                return Err(EvalError::new(format!(
+                    "make-vector: length {} is too long, the most is {}",
+                    n, MAX_VECTOR_LENGTH
+                )));
+            }
+            let mut items = Vec::new();
+            items.try_reserve_exact(n).map_err(|_| {
+                EvalError::new(format!("make-vector: out of memory for length {}", n))
+            })?;
+            items.resize(n, fill);
+            Ok(Value::Vector(Vector::new(items)))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("vector-length"),
+        Value::Callable(|_, values| match one("vector-length", values)? {
+            Value::Vector(vector) => Ok(Value::int(vector.len() as i64)),
+            other => Err(EvalError::wrong_type("vector-length", "vector", &other, 1)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("vector-ref"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Vector(vector), k] => {
+                let k = vector_index("vector-ref", vector, k)?;
+                Ok(vector.get(k).unwrap())
+            }
+            [other, _] => Err(EvalError::wrong_type("vector-ref", "vector", other, 1)),
+            _ => Err(EvalError::arity("vector-ref", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("vector-set!"),
+        Value::Callable(|_, values| match &values[..] {
+            [Value::Vector(vector), k, value] => {
+                let k = vector_index("vector-set!", vector, k)?;
+                vector.set(k, value.clone());
+                Ok(value.clone())
+            }
+            [other, _, _] => Err(EvalError::wrong_type("vector-set!", "vector", other, 1)),
+            _ => Err(EvalError::arity("vector-set!", "3", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("make-hash-table"),
+        Value::Callable(|_, values| match values.len() {
+            0 => Ok(Value::HashTable(HashTable::new())),
+            n => Err(EvalError::arity("make-hash-table", "0", n)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("hash-ref"),
+        Value::Callable(|_, values| {
+            let default = match values.len() {
+                2 => Value::Nil,
+                3 => values[2].clone(),
+                n => return Err(EvalError::arity("hash-ref", "2 or 3", n)),
+            };
+            let table = table_arg("hash-ref", &values[0])?;
+            let key = values[1].clone().into_key("hash-ref", 2)?;
+            Ok(table.get(&key).unwrap_or(default))
+        }),
+    );
+
+    env.define(
+        Symbol::intern("hash-set!"),
+        Value::Callable(|_, values| match &values[..] {
+            [table, key, value] => {
+                let table = table_arg("hash-set!", table)?;
+                table.insert(key.clone().into_key("hash-set!", 2)?, value.clone());
+                Ok(value.clone())
+            }
+            _ => Err(EvalError::arity("hash-set!", "3", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("hash-remove!"),
+        Value::Callable(|_, values| match &values[..] {
+            [table, key] => {
+                let table = table_arg("hash-remove!", table)?;
+                let key = key.clone().into_key("hash-remove!", 2)?;
+                Ok(table.remove(&key).unwrap_or(Value::Nil))
+            }
+            _ => Err(EvalError::arity("hash-remove!", "2", values.len())),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("hash-keys"),
+        Value::Callable(|_, values| {
+            let table = one("hash-keys", values)?;
+            let table = table_arg("hash-keys", &table)?;
+            Ok(table.keys().into_iter().map(Value::from).collect())
+        }),
+    );
+
+    env.define(
+        Symbol::intern("gc"),
+        Value::Callable(|_, values| match values.len() {
+            0 => Ok(Value::int(gc::collect() as i64)),
+            n => Err(EvalError::arity("gc", "0", n)),
+        }),
+    );
+
+    env.define(
+        Symbol::intern("gc-stats"),
+        Value::Callable(|_, values| match values.len() {
+            0 => {
+                let stats = gc::stats();
+                let field = |name: &str, n: usize| {
+                    Value::Cons(Cons::new(Value::Symbol(name.into()), Value::int(n as i64)))
+                };
+                Ok(vec![
+                    field("live", stats.live),
+                    field("bytes", stats.bytes),
+                    field("collections", stats.collections),
+                    field("freed", stats.freed),
+                ]
+                .into_iter()
+                .collect())
+            }
+            n => Err(EvalError::arity("gc-stats", "0", n)),
+        }),
+    );
+
+    env.define(Symbol::intern("T"), Value::Bool(true));
+
+    env.define(Symbol::intern("t"), Value::Bool(true));
 
     env
 }
 
+/// Write to the output of the environment `ctx` was called from.
+fn emit(ctx: &Context, name: &str, args: fmt::Arguments) -> Result<(), EvalError> {
+    ctx.env()
+        .output()
+        .borrow_mut()
+        .write_fmt(args)
+        .map_err(|e| EvalError::new(format!("{}: {}", name, e)))
+}
+
 fn last_or_nil(values: Vec<Value>) -> Value {
     values.last().cloned().unwrap_or(Value::Nil)
 }
 
-struct Values(Vec<Value>);
-impl Values {
-    pub fn from(v: Vec<Value>) -> Self {
-        Self(v)
+/// The function and lists that `map` and `for-each` take.
+fn func_and_lists(
+    name: &str,
+    mut values: Vec<Value>,
+) -> Result<(Value, Vec<Vec<Value>>), EvalError> {
+    if values.len() < 2 {
+        return Err(EvalError::arity(name, "at least 2", values.len()));
     }
+    let func = values.remove(0);
+    let lists = values
+        .into_iter()
+        .enumerate()
+        .map(|(i, list)| list_to_vec(list, name, i + 2))
+        .collect::<Result<_, _>>()?;
+    Ok((func, lists))
+}
 
-    pub fn to_tuples(&self) -> Option<Vec<(Value, Value)>> {
-        if self.0.len() < 2 {
-            None
-        } else {
-            let mut ret = Vec::new();
-            for (i, v) in self.0.iter().enumerate() {
-                match self.0.iter().nth(i + 1) {
-                    Some(n) => ret.push((v.clone(), n.clone())),
-                    None => break,
+/// The i-th items of `lists`, for each i up to the length of the shortest.
+fn zip_lists(lists: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    let len = lists.iter().map(Vec::len).min().unwrap_or(0);
+    let mut iters = lists.into_iter().map(Vec::into_iter).collect::<Vec<_>>();
+    (0..len)
+        .map(|_| iters.iter_mut().map(|iter| iter.next().unwrap()).collect())
+        .collect()
+}
+
+/// The only argument of `name`.
+fn one(name: &str, values: Vec<Value>) -> EvalResult {
+    let given = values.len();
+    values
+        .into_iter()
+        .exactly_one()
+        .map_err(|_| EvalError::arity(name, "1", given))
+}
+
+/// The first argument of `name`, which must be a hash table.
+fn table_arg<'a>(name: &str, value: &'a Value) -> Result<&'a HashTable, EvalError> {
+    match value {
+        Value::HashTable(table) => Ok(table),
+        other => Err(EvalError::wrong_type(name, "hash-table", other, 1)),
+    }
+}
+
+/// `k`, the second argument of `name`, as an index into `vector`.
+fn vector_index(name: &str, vector: &Vector, k: &Value) -> Result<usize, EvalError> {
+    let k = k.clone().into_int(name, 2)?;
+    usize::try_from(k)
+        .ok()
+        .filter(|&k| k < vector.len())
+        .ok_or_else(|| {
+            EvalError::new(format!(
+                "{}: index {} is out of range for a vector of length {}",
+                name,
+                k,
+                vector.len()
+            ))
+        })
+}
+
+/// `value`, the argument at 1-based `position` of `name`, as a string.
+fn str_arg<'a>(name: &str, value: &'a Value, position: usize) -> Result<&'a str, EvalError> {
+    match value {
+        Value::Str(s) => Ok(s),
+        other => Err(EvalError::wrong_type(name, "string", other, position)),
+    }
+}
+
+fn strs<'a>(name: &str, values: &'a [Value]) -> Result<Vec<&'a str>, EvalError> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| str_arg(name, value, i + 1))
+        .collect()
+}
+
+/// Like `compare`, for strings.
+fn compare_strs(name: &str, values: Vec<Value>, op: fn(Ordering) -> bool) -> EvalResult {
+    let strs = strs(name, &values)?;
+    Ok(Value::Bool(strs.windows(2).all(|w| op(w[0].cmp(w[1])))))
+}
+
+/// `(format template args...)`: `template` with `~a` replaced by the next
+/// argument as `display` shows it, `~s` as `write` does, `~%` by a newline
+/// and `~~` by `~`.
+fn format(values: Vec<Value>) -> EvalResult {
+    use std::fmt::Write;
+    let (template, args) = values
+        .split_first()
+        .ok_or_else(|| EvalError::arity("format", "at least 1", 0))?;
+    let template = str_arg("format", template, 1)?;
+    let mut args = args.iter();
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(directive @ 'a') | Some(directive @ 's') => {
+                let arg = args
+                    .next()
+                    .ok_or_else(|| EvalError::new("format: too few arguments"))?;
+                if directive == 'a' {
+                    write!(out, "{}", print::display(arg))
+                } else {
+                    write!(out, "{}", print::write(arg))
                 }
+                .expect("writing to a String");
             }
-            Some(ret)
+            Some('%') => out.push('\n'),
+            Some('~') => out.push('~'),
+            Some(c) => return Err(EvalError::new(format!("format: unknown directive ~{}", c))),
+            None => return Err(EvalError::new("format: `~` at end of template")),
+        }
+    }
+    if args.next().is_some() {
+        return Err(EvalError::new("format: too many arguments"));
+    }
+    Ok(Value::Str(out))
+}
+
+fn nums(name: &str, values: &[Value]) -> Result<Vec<Number>, EvalError> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| value.clone().into_num(name, i + 1))
+        .collect()
+}
+
+/// `f` applied to the one numeric argument of `name`.
+fn unary(name: &str, values: Vec<Value>, f: fn(&Number) -> Number) -> EvalResult {
+    let n = one(name, values)?.into_num(name, 1)?;
+    Ok(Value::Number(f(&n)))
+}
+
+/// `f` applied to the two numeric arguments of `name`, where `None` means
+/// a division by zero.
+fn binary(name: &str, values: Vec<Value>, f: fn(&Number, &Number) -> Option<Number>) -> EvalResult {
+    match &nums(name, &values)?[..] {
+        [a, b] => f(a, b)
+            .map(Value::Number)
+            .ok_or_else(|| EvalError::from(ErrorKind::DivisionByZero)),
+        _ => Err(EvalError::arity(name, "2", values.len())),
+    }
+}
+
+/// The float function `f` of the one numeric argument of `name`.
+fn float(name: &str, values: Vec<Value>, f: fn(f64) -> f64) -> EvalResult {
+    let n = one(name, values)?.into_num(name, 1)?;
+    Ok(Value::Number(Number::Float(f(n.to_f64()))))
+}
+
+/// The argument that compares as `keep` to all others, inexact if any is.
+fn extremum(name: &str, values: Vec<Value>, keep: Ordering) -> EvalResult {
+    let nums = nums(name, &values)?;
+    let (first, rest) = nums
+        .split_first()
+        .ok_or_else(|| EvalError::arity(name, "at least 1", 0))?;
+    let mut best = first;
+    for n in rest {
+        if n.compare(best) == Some(keep) {
+            best = n;
         }
     }
+    Ok(Value::Number(if nums.iter().all(Number::is_exact) {
+        best.clone()
+    } else {
+        Number::Float(best.to_f64())
+    }))
+}
+
+fn divide(n: &Number, m: &Number) -> Result<Number, EvalError> {
+    n.div(m)
+        .ok_or_else(|| EvalError::from(ErrorKind::DivisionByZero))
+}
+
+/// Whether every adjacent pair of `values` compares as `op` accepts, so `#t`
+/// for a single value; `#f` when a NaN makes a pair unordered.
+fn compare(name: &str, values: Vec<Value>, op: fn(Ordering) -> bool) -> EvalResult {
+    if values.is_empty() {
+        return Err(EvalError::arity(name, "at least 1", 0));
+    }
+    let nums = nums(name, &values)?;
+    Ok(Value::Bool(
+        nums.windows(2).all(|w| w[0].compare(&w[1]).is_some_and(op)),
+    ))
 }
@@ -1,134 +1,1662 @@
 use super::ast;
-use super::env::make_global_env;
+use super::env::{make_global_env, Env, Input, Output};
+use super::gc;
+use super::native::Native;
+use super::number::Number;
+use super::parse;
+use super::print;
+use super::quote;
+use super::report;
+use super::stack;
+use super::symbol::Symbol;
 
-use std::collections::HashMap;
+use codespan::Span;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::iter::FromIterator;
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Cons(Box<Value>, Box<Value>);
+/// A shared, mutable pair. Clones refer to the same cell, so `set-car!`
+/// through one is seen by all; `==` compares contents, `Value::is_eq` identity.
+#[derive(Debug, Clone)]
+pub struct Cons(Rc<RefCell<(Value, Value)>>);
 
 impl Cons {
     pub fn new(car: Value, cdr: Value) -> Self {
-        Cons(Box::new(car), Box::new(cdr))
+        let cell = Rc::new(RefCell::new((car, cdr)));
+        gc::register(&cell);
+        Cons(cell)
     }
 
     pub fn car(&self) -> Value {
-        *self.0.clone()
+        self.0.borrow().0.clone()
     }
 
     pub fn cdr(&self) -> Value {
-        *self.1.clone()
+        self.0.borrow().1.clone()
+    }
+
+    pub fn set_car(&self, value: Value) {
+        self.0.borrow_mut().0 = value;
     }
 
-    pub fn append(&mut self, value: Value) -> Self {
-        eprintln!("{:?} <= {:?}", self.clone(), value.clone());
-        match *self.1.clone() {
-            Value::Nil => {
-                self.1 = Box::new(Value::Cons(Cons::new(value, Value::Nil)));
-                self.clone()
+    pub fn set_cdr(&self, value: Value) {
+        self.0.borrow_mut().1 = value;
+    }
+
+    /// Whether `self` and `other` are the same cell.
+    pub fn ptr_eq(&self, other: &Cons) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn addr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl gc::Trace for RefCell<(Value, Value)> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(pair) = self.try_borrow() {
+            gc::trace_value(&pair.0, visit);
+            gc::trace_value(&pair.1, visit);
+        }
+    }
+
+    fn clear(&self) {
+        let pair = std::mem::replace(&mut *self.borrow_mut(), (Value::Nil, Value::Nil));
+        drop(pair);
+    }
+}
+
+impl PartialEq for Cons {
+    fn eq(&self, other: &Self) -> bool {
+        equal(Value::Cons(self.clone()), Value::Cons(other.clone()))
+    }
+}
+
+/// Structural equality of conses and vectors. The pairs still to compare are
+/// kept on a stack so long lists don't recurse, and a pair of cells already
+/// being compared is taken to be equal so circular structures terminate.
+fn equal(a: Value, b: Value) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![(a, b)];
+    while let Some(pair) = stack.pop() {
+        match pair {
+            (Value::Cons(a), Value::Cons(b)) => {
+                if a.ptr_eq(&b) || !seen.insert((a.addr(), b.addr())) {
+                    continue;
+                }
+                stack.push((a.cdr(), b.cdr()));
+                stack.push((a.car(), b.car()));
+            }
+            (Value::Vector(a), Value::Vector(b)) => {
+                if a.ptr_eq(&b) || !seen.insert((a.addr(), b.addr())) {
+                    continue;
+                }
+                let (a, b) = (a.to_vec(), b.to_vec());
+                if a.len() != b.len() {
+                    return false;
+                }
+                stack.extend(a.into_iter().zip(b).rev());
+            }
+            (a, b) => {
+                if a != b {
+                    return false;
+                }
             }
-            Value::Cons(mut cons) => cons.append(value),
-            _ => panic!("Can't append"),
         }
     }
+    true
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl Drop for Cons {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) == 1 {
+            let (car, cdr) = std::mem::replace(&mut *self.0.borrow_mut(), (Value::Nil, Value::Nil));
+            if has_children(&car) || has_children(&cdr) {
+                drop_children(vec![car, cdr]);
+            }
+        }
+    }
+}
+
+/// Whether dropping `value` can drop other values in turn.
+fn has_children(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Cons(_) | Value::Vector(_) | Value::HashTable(_)
+    )
+}
+
+/// Drop `values` and the conses, vectors and hash tables only they refer to,
+/// one by one from a worklist: dropping a long list, or data nested deeply
+/// through `car`s or vectors, recursively would overflow the stack.
+fn drop_children(mut values: Vec<Value>) {
+    while let Some(value) = values.pop() {
+        match &value {
+            Value::Cons(cons) if Rc::strong_count(&cons.0) == 1 => {
+                let (car, cdr) =
+                    std::mem::replace(&mut *cons.0.borrow_mut(), (Value::Nil, Value::Nil));
+                values.push(car);
+                values.push(cdr);
+            }
+            Value::Vector(vector) if Rc::strong_count(&vector.0) == 1 => {
+                values.append(&mut vector.0.borrow_mut());
+            }
+            Value::HashTable(table) if Rc::strong_count(&table.0) == 1 => {
+                values.extend(table.0.borrow_mut().drain().map(|(_, value)| value));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A shared, mutable vector. Like `Cons`, clones refer to the same storage
+/// and `==` compares contents.
+#[derive(Debug, Clone)]
+pub struct Vector(Rc<RefCell<Vec<Value>>>);
+
+impl Vector {
+    pub fn new(items: Vec<Value>) -> Self {
+        let items = Rc::new(RefCell::new(items));
+        gc::register(&items);
+        Vector(items)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        self.0.borrow().get(index).cloned()
+    }
+
+    /// Replace the item at `index`, or return `None` if it is out of range.
+    pub fn set(&self, index: usize, value: Value) -> Option<()> {
+        *self.0.borrow_mut().get_mut(index)? = value;
+        Some(())
+    }
+
+    pub fn to_vec(&self) -> Vec<Value> {
+        self.0.borrow().clone()
+    }
+
+    pub fn ptr_eq(&self, other: &Vector) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn addr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl PartialEq for Vector {
+    fn eq(&self, other: &Self) -> bool {
+        equal(Value::Vector(self.clone()), Value::Vector(other.clone()))
+    }
+}
+
+impl Drop for Vector {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) == 1 && self.0.borrow().iter().any(has_children) {
+            drop_children(std::mem::take(&mut *self.0.borrow_mut()));
+        }
+    }
+}
+
+impl gc::Trace for RefCell<Vec<Value>> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(items) = self.try_borrow() {
+            items.iter().for_each(|item| gc::trace_value(item, visit));
+        }
+    }
+
+    fn clear(&self) {
+        let items = std::mem::take(&mut *self.borrow_mut());
+        drop(items);
+    }
+}
+
+/// A hash table key: the atoms that `equal?` compares structurally.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Key {
+    Number(Number),
+    Str(String),
+    Symbol(Symbol),
+    Char(char),
+    Bool(bool),
+}
+
+impl From<Key> for Value {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Number(n) => Value::Number(n),
+            Key::Str(s) => Value::Str(s),
+            Key::Symbol(s) => Value::Symbol(s),
+            Key::Char(c) => Value::Char(c),
+            Key::Bool(b) => Value::Bool(b),
+        }
+    }
+}
+
+/// A shared, mutable hash table. Unlike vectors, tables are only equal to
+/// themselves.
+#[derive(Debug, Clone)]
+pub struct HashTable(Rc<RefCell<HashMap<Key, Value>>>);
+
+impl HashTable {
+    pub fn new() -> Self {
+        let table = Rc::new(RefCell::new(HashMap::new()));
+        gc::register(&table);
+        HashTable(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Value> {
+        self.0.borrow().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: Key, value: Value) -> Option<Value> {
+        self.0.borrow_mut().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &Key) -> Option<Value> {
+        self.0.borrow_mut().remove(key)
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        self.0.borrow().keys().cloned().collect()
+    }
+
+    pub fn ptr_eq(&self, other: &HashTable) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn addr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl Drop for HashTable {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) == 1 && self.0.borrow().values().any(has_children) {
+            let values = self
+                .0
+                .borrow_mut()
+                .drain()
+                .map(|(_, value)| value)
+                .collect();
+            drop_children(values);
+        }
+    }
+}
+
+impl Default for HashTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl gc::Trace for RefCell<HashMap<Key, Value>> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(table) = self.try_borrow() {
+            table
+                .values()
+                .for_each(|value| gc::trace_value(value, visit));
+        }
+    }
+
+    fn clear(&self) {
+        let table = std::mem::take(&mut *self.borrow_mut());
+        drop(table);
+    }
+}
+
+/// A source of input or a destination of output. Ports are only equal to
+/// themselves.
+#[derive(Clone)]
+pub enum Port {
+    Input(Input),
+    Output(Output),
+}
+
+impl PartialEq for Port {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Port::Input(a), Port::Input(b)) => Rc::ptr_eq(a, b),
+            (Port::Output(a), Port::Output(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Port::Input(_) => write!(f, "Port::Input"),
+            Port::Output(_) => write!(f, "Port::Output"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lambda {
+    pub name: Option<String>,
+    pub params: Vec<Symbol>,
+    /// parameter after `&rest`, bound to the list of remaining arguments
+    pub rest: Option<Symbol>,
+    pub body: ast::Body,
+    pub env: Env,
+}
+
+impl gc::Trace for Lambda {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        visit(self.env.addr());
+    }
+
+    /// A cycle through a closure also runs through its environment, which
+    /// is cleared instead.
+    fn clear(&self) {}
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
-    Number(i64),
+    Number(Number),
     Callable(Callable),
+    /// a host function, see `Interpreter::register_fn`
+    Native(Rc<Native>),
+    Closure(Rc<Lambda>),
+    Macro(Rc<Lambda>),
     Cons(Cons),
+    Vector(Vector),
+    HashTable(HashTable),
+    Port(Port),
     Str(String),
+    Bool(bool),
+    Char(char),
+    Symbol(Symbol),
     Nil,
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Callable(a), Value::Callable(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Macro(a), Value::Macro(b)) => Rc::ptr_eq(a, b),
+            (Value::Cons(a), Value::Cons(b)) => a == b,
+            (Value::Vector(a), Value::Vector(b)) => a == b,
+            (Value::HashTable(a), Value::HashTable(b)) => a.ptr_eq(b),
+            (Value::Port(a), Value::Port(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        }
+    }
+}
+
 impl Value {
+    /// Identity, as `eq?` sees it: conses and vectors are the same storage,
+    /// atoms are equal.
+    pub fn is_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Cons(a), Value::Cons(b)) => a.ptr_eq(b),
+            (Value::Vector(a), Value::Vector(b)) => a.ptr_eq(b),
+            (a, b) => a == b,
+        }
+    }
+
+    /// Only `#f` and `nil` are false; everything else, including `0`, the
+    /// empty string and `#t`, is true.
     pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false) | Value::Nil)
+    }
+
+    pub fn int(n: i64) -> Value {
+        Value::Number(Number::Int(n))
+    }
+
+    /// `name` and the 1-based `position` describe the argument on a type error.
+    pub fn into_num(self, name: &str, position: usize) -> Result<Number, EvalError> {
+        match self {
+            Value::Number(n) => Ok(n),
+            other => Err(EvalError::wrong_type(name, "number", &other, position)),
+        }
+    }
+
+    /// Like `into_num`, for arguments that must be a fixnum, e.g. an index.
+    pub fn into_int(self, name: &str, position: usize) -> Result<i64, EvalError> {
+        match self.clone().into_num(name, position)? {
+            Number::Int(n) => Ok(n),
+            _ => Err(EvalError::wrong_type(name, "integer", &self, position)),
+        }
+    }
+
+    /// Like `into_num`, for hash table keys.
+    pub fn into_key(self, name: &str, position: usize) -> Result<Key, EvalError> {
         match self {
-            Value::Nil => false,
-            Value::Number(n) => *n != 0,
-            _ => true,
+            Value::Number(n) => Ok(Key::Number(n)),
+            Value::Str(s) => Ok(Key::Str(s)),
+            Value::Symbol(s) => Ok(Key::Symbol(s)),
+            Value::Char(c) => Ok(Key::Char(c)),
+            Value::Bool(b) => Ok(Key::Bool(b)),
+            other => Err(EvalError::wrong_type(name, "hash key", &other, position)),
         }
     }
 
-    pub fn into_num(self) -> i64 {
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Cons(cons) => cons.0.into_num(),
-            Value::Number(n) => n,
-            Value::Nil => 0,
-            other => panic!("Can't use {:?}, it isn't number", other),
+            Value::Number(_) => "number",
+            Value::Callable(_) | Value::Native(_) => "builtin",
+            Value::Closure(_) => "function",
+            Value::Macro(_) => "macro",
+            Value::Cons(_) => "cons",
+            Value::Vector(_) => "vector",
+            Value::HashTable(_) => "hash-table",
+            Value::Port(_) => "port",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::Char(_) => "char",
+            Value::Symbol(_) => "symbol",
+            Value::Nil => "nil",
         }
     }
 }
 
+/// The `write` form, which reads back as the same value for data.
 impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        print::write(self).fmt(f)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    Message(String),
+    WrongType {
+        name: String,
+        expected: &'static str,
+        actual: &'static str,
+        /// 1-based argument position
+        position: usize,
+    },
+    Arity {
+        name: String,
+        expected: String,
+        given: usize,
+    },
+    DivisionByZero,
+    /// `(exit code)`, for the host to act on
+    Exit(i32),
+}
+
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Callable(c) => write!(f, "<callable {:x?}>", c),
-            Value::Cons(c) => write!(f, "({}, {})", c.0, c.1),
-            Value::Str(s) => write!(f, "\"{}\"", s),
-            Value::Nil => write!(f, "Nil"),
+            ErrorKind::Message(message) => write!(f, "{}", message),
+            ErrorKind::WrongType {
+                name,
+                expected,
+                actual,
+                position,
+            } => write!(
+                f,
+                "Wrong argument type: {} expects {} as argument {}, got {}",
+                name, expected, position, actual
+            ),
+            ErrorKind::Arity {
+                name,
+                expected,
+                given,
+            } => write!(
+                f,
+                "Wrong number of arguments: {} expects {}, got {}",
+                name, expected, given
+            ),
+            ErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            ErrorKind::Exit(code) => write!(f, "exit with status {}", code),
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct EvalError(pub String);
+pub struct EvalError {
+    pub kind: ErrorKind,
+    /// span of the innermost expression that failed
+    pub span: Option<Span>,
+    /// user function calls that were active, innermost first
+    pub stack: Vec<StackFrame>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackFrame {
+    pub name: String,
+    /// span of the call expression
+    pub span: Span,
+}
+
+/// Innermost stack frames shown by `EvalError::report`; a runaway recursion
+/// would otherwise print a note per level.
+const MAX_REPORTED_FRAMES: usize = 32;
+
+impl EvalError {
+    pub fn new(message: impl Into<String>) -> Self {
+        EvalError::from(ErrorKind::Message(message.into()))
+    }
+
+    pub fn wrong_type(
+        name: impl Into<String>,
+        expected: &'static str,
+        actual: &Value,
+        position: usize,
+    ) -> Self {
+        EvalError::from(ErrorKind::WrongType {
+            name: name.into(),
+            expected,
+            actual: actual.type_name(),
+            position,
+        })
+    }
+
+    pub fn arity(name: impl Into<String>, expected: impl Into<String>, given: usize) -> Self {
+        EvalError::from(ErrorKind::Arity {
+            name: name.into(),
+            expected: expected.into(),
+            given,
+        })
+    }
+
+    fn at(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    fn called_from(mut self, name: String, span: Span) -> Self {
+        self.stack.push(StackFrame { name, span });
+        self
+    }
+
+    /// Render the error against `source` (named `name`) that the failing
+    /// expressions were parsed from, with one note per stack frame up to
+    /// `MAX_REPORTED_FRAMES`.
+    pub fn report(&self, name: &str, source: &str) -> String {
+        let mut notes = self
+            .stack
+            .iter()
+            .take(MAX_REPORTED_FRAMES)
+            .map(|frame| {
+                let (line, column) = report::location(source, frame.span);
+                format!("in `{}` called at {}:{}:{}", frame.name, name, line, column)
+            })
+            .collect::<Vec<_>>();
+        if self.stack.len() > MAX_REPORTED_FRAMES {
+            notes.push(format!(
+                "... and {} more",
+                self.stack.len() - MAX_REPORTED_FRAMES
+            ));
+        }
+        match self.span {
+            Some(span) => report::render(name, source, &self.kind.to_string(), span, notes),
+            None => format!("error: {}\n", self.kind),
+        }
+    }
+}
+
+impl From<ErrorKind> for EvalError {
+    fn from(kind: ErrorKind) -> Self {
+        EvalError {
+            kind,
+            span: None,
+            stack: Vec::new(),
+        }
+    }
+}
 
 impl fmt::Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "error: {}", self.0)
+        write!(f, "error: {}", self.kind)
     }
 }
 
 pub type EvalResult = Result<Value, EvalError>;
 
-type Callable = fn(Vec<Value>) -> EvalResult;
+/// A builtin, called with the evaluator's `Context` and its arguments.
+pub type Callable = fn(&Context, Vec<Value>) -> EvalResult;
+
+/// What a builtin can reach of the evaluator that calls it.
+pub struct Context<'a> {
+    env: &'a Env,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        Context { env }
+    }
+
+    /// The environment the builtin was called from.
+    pub fn env(&self) -> &Env {
+        self.env
+    }
+
+    /// Call `func`, a builtin or a closure, with already evaluated `args`.
+    pub fn call(&self, func: &Value, args: Vec<Value>) -> EvalResult {
+        apply(func.clone(), args, self.env)
+    }
+}
 
-pub fn eval(expr: ast::Expr) -> EvalResult {
-    eval_with_env(expr, &mut make_global_env())
+pub fn eval(expr: &ast::Expr) -> EvalResult {
+    eval_with_env(expr, &make_global_env())
+}
+
+/// Most recent tail calls kept for error reports; a long tail-recursive loop
+/// would otherwise grow the trace without bound.
+const MAX_TAIL_FRAMES: usize = 64;
+
+/// Most nested evaluations, e.g. non-tail calls, before giving up. The stack
+/// they need grows as they go (see `stack`), so this bounds a runaway
+/// recursion, not the thread evaluation happens to run on.
+pub const MAX_DEPTH: usize = 10_000;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// One level of `DEPTH`, left when dropped.
+struct DepthGuard;
+
+impl DepthGuard {
+    fn enter() -> Result<Self, EvalError> {
+        DEPTH.with(|depth| {
+            if depth.get() >= MAX_DEPTH {
+                return Err(EvalError::new("eval: maximum recursion depth exceeded"));
+            }
+            depth.set(depth.get() + 1);
+            Ok(DepthGuard)
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Evaluate `expr` in `env`. Expressions in tail position (`if` branches, the
+/// last form of a body, macro expansions) are evaluated by the loop here
+/// rather than by recursion, so tail calls run in constant stack space.
+/// Other nested evaluations fail past `MAX_DEPTH`.
+pub fn eval_with_env(expr: &ast::Expr, env: &Env) -> EvalResult {
+    let _depth = DepthGuard::enter().map_err(|e| e.at(expr.span()))?;
+    stack::grow(|| eval_loop(expr, env))
+}
+
+fn eval_loop(expr: &ast::Expr, env: &Env) -> EvalResult {
+    let mut tail: Option<Rc<ast::Expr>> = None;
+    let mut env = env.clone();
+    let mut frames = Vec::new();
+    loop {
+        gc::maybe_collect();
+        let current = tail.as_deref().unwrap_or(expr);
+        match step(current, &env) {
+            Ok(Step::Done(value)) => return Ok(value),
+            Ok(Step::Tail(next, next_env, frame)) => {
+                tail = Some(next);
+                env = next_env;
+                if let Some(frame) = frame {
+                    if frames.len() == MAX_TAIL_FRAMES {
+                        frames.remove(0);
+                    }
+                    frames.push(frame);
+                }
+            }
+            Err(e) => {
+                let mut e = e.at(current.span());
+                e.stack.extend(frames.into_iter().rev());
+                return Err(e);
+            }
+        }
+    }
 }
 
-pub fn eval_with_env(expr: ast::Expr, env: &mut HashMap<String, Value>) -> EvalResult {
+enum Step {
+    Done(Value),
+    /// continue with an expression in tail position, in the given environment,
+    /// entered through a call of the given function if any
+    Tail(Rc<ast::Expr>, Env, Option<StackFrame>),
+}
+
+fn step(expr: &ast::Expr, env: &Env) -> Result<Step, EvalError> {
     use ast::Expr::*;
-    match expr {
+    let value = match expr {
         Symbol(_, s) => env
-            .get(&s)
-            .cloned()
-            .ok_or_else(|| EvalError(format!("eval: Undefined symbol {}", s))),
-        Number(_, n) => Ok(Value::Number(n)),
-        Str(s) => Ok(Value::Str(s)),
+            .get(*s)
+            .ok_or_else(|| EvalError::new(format!("eval: Undefined symbol {}", s)))?,
+        Number(_, n) => Value::Number(n.clone()),
+        Str(_, s) => Value::Str(s.clone()),
+        Bool(_, b) => Value::Bool(*b),
+        Char(_, c) => Value::Char(*c),
+        Nil(..) => Value::Nil,
+        Vector(_, vector) => Value::Vector(vector.clone()),
+        Quote(_, datum) => datum.clone(),
+        Quasiquote(_, template) => quote::quasiquote(template, env)?,
+        Unquote(..) | UnquoteSplicing(..) => {
+            return Err(EvalError::new("unquote outside of quasiquote"))
+        }
         If(_, _, cond, true_then, false_then, _) => {
-            let expr = if eval_with_env(*cond, env)?.is_truthy() {
+            let expr = if eval_with_env(cond, env)?.is_truthy() {
                 true_then
             } else {
                 false_then
             };
-            Ok(eval_with_env(*expr, env)?)
+            return Ok(Step::Tail(expr.clone(), env.clone(), None));
         }
+        Begin(_, _, body, _) => return tail_body(body, env.clone()),
         Define(_, _, sym, value, _) => {
-            let value = eval_with_env(*value, env)?;
-            let sym = to_sym(sym)?;
-            env.insert(sym, value.clone());
-            Ok(value)
+            let value = eval_with_env(value, env)?;
+            env.set(to_sym(sym)?, value.clone());
+            value
+        }
+        Lambda(_, _, params, body, _) => Value::Closure(make_lambda(None, params, body, env)?),
+        Defun(_, _, name, params, body, _) => {
+            let name = to_sym(name)?;
+            let closure = Value::Closure(make_lambda(Some(name.to_string()), params, body, env)?);
+            env.set(name, closure.clone());
+            closure
+        }
+        Defmacro(_, _, name, params, body, _) => {
+            let name = to_sym(name)?;
+            let lambda = make_lambda(Some(name.to_string()), params, body, env)?;
+            env.set(name, Value::Macro(lambda.clone()));
+            Value::Macro(lambda)
+        }
+        Let(_, _, bindings, body, _) => {
+            let local = Env::with_parent(env);
+            for (sym, value) in bindings {
+                local.define(to_sym(sym)?, eval_with_env(value, env)?);
+            }
+            return tail_body(body, local);
+        }
+        LetStar(_, _, bindings, body, _) => {
+            let local = Env::with_parent(env);
+            for (sym, value) in bindings {
+                let value = eval_with_env(value, &local)?;
+                local.define(to_sym(sym)?, value);
+            }
+            return tail_body(body, local);
+        }
+        Letrec(_, _, bindings, body, _) => {
+            let local = Env::with_parent(env);
+            for (sym, _) in bindings {
+                local.define(to_sym(sym)?, Value::Nil);
+            }
+            for (sym, value) in bindings {
+                let value = eval_with_env(value, &local)?;
+                local.define(to_sym(sym)?, value);
+            }
+            return tail_body(body, local);
+        }
+        Call(open, head, args, close, expansion) => {
+            let span = open.span().merge(close.span());
+            let func = match &**head {
+                Symbol(_, sym) => env
+                    .get(*sym)
+                    .ok_or_else(|| EvalError::new(format!("eval: Invalid function {}", sym)))?,
+                head => eval_with_env(head, env)?,
+            };
+            if let Value::Macro(lambda) = func {
+                if let Some(expr) = expansion.get(&lambda) {
+                    return Ok(Step::Tail(expr, env.clone(), None));
+                }
+                let args = args
+                    .iter()
+                    .map(quote::quote)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let expanded = call_lambda(&lambda, args)?;
+                let expr = parse::from_value(&expanded, span)
+                    .map_err(|e| EvalError::new(format!("macro expansion: {}", e.message)))?;
+                let expr = Rc::new(expr);
+                expansion.set(lambda, expr.clone());
+                return Ok(Step::Tail(expr, env.clone(), None));
+            }
+            let args = args
+                .iter()
+                .map(|expr| eval_with_env(expr, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            match func {
+                Value::Closure(lambda) => {
+                    let frame = StackFrame {
+                        name: lambda.name.clone().unwrap_or_else(|| "lambda".to_string()),
+                        span,
+                    };
+                    let local = bind_args(&lambda, args)?;
+                    return match tail_body(&lambda.body, local) {
+                        Ok(Step::Tail(expr, env, _)) => Ok(Step::Tail(expr, env, Some(frame))),
+                        Ok(done) => Ok(done),
+                        Err(e) => Err(e.at(span).called_from(frame.name, span)),
+                    };
+                }
+                func => apply(func, args, env)?,
+            }
+        }
+    };
+    Ok(Step::Done(value))
+}
+
+/// Evaluate all but the last form of `body`, which is left in tail position.
+fn tail_body(body: &[Rc<ast::Expr>], env: Env) -> Result<Step, EvalError> {
+    match body.split_last() {
+        Some((last, init)) => {
+            for expr in init {
+                eval_with_env(expr, &env)?;
+            }
+            Ok(Step::Tail(last.clone(), env, None))
         }
-        Call(_, sym, args, _) => {
-            let sym = to_sym(sym)?;
-            match env.get(&sym) {
-                Some(Value::Callable(c)) => c(args
-                    .into_iter()
-                    .map(|expr| eval_with_env(expr, env))
-                    .collect::<Result<Vec<_>, _>>()?),
-                _ => Err(EvalError(format!("eval: Invalid function {}", sym))),
+        None => Ok(Step::Done(Value::Nil)),
+    }
+}
+
+fn make_lambda(
+    name: Option<String>,
+    params: &[ast::Token],
+    body: &ast::Body,
+    env: &Env,
+) -> Result<Rc<Lambda>, EvalError> {
+    let mut params = params.iter().map(to_sym).collect::<Result<Vec<_>, _>>()?;
+    let rest = match params.iter().position(|p| p == "&rest") {
+        Some(i) if i + 2 == params.len() => {
+            let rest = params.pop();
+            params.pop();
+            rest
+        }
+        Some(_) => return Err(EvalError::new("&rest must be followed by one parameter")),
+        None => None,
+    };
+    let lambda = Rc::new(Lambda {
+        name,
+        params,
+        rest,
+        body: body.clone(),
+        env: env.clone(),
+    });
+    gc::register(&lambda);
+    Ok(lambda)
+}
+
+/// Expand `form` once if it is a call to a macro bound in `env`.
+pub fn macroexpand_1(form: &Value, env: &Env) -> Result<Option<Value>, EvalError> {
+    if let Value::Cons(cons) = form {
+        if let Value::Symbol(name) = cons.car() {
+            if let Some(Value::Macro(lambda)) = env.get(name) {
+                let args = list_to_vec(cons.cdr(), name.as_str(), 1)?;
+                return call_lambda(&lambda, args).map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Call `func` with already evaluated `args`, from `env`.
+/// A closure runs in a new frame whose parent is its defining environment.
+pub fn apply(func: Value, args: Vec<Value>, env: &Env) -> EvalResult {
+    match func {
+        Value::Callable(c) => c(&Context::new(env), args),
+        Value::Native(native) => native.call(&args),
+        Value::Closure(lambda) => call_lambda(&lambda, args),
+        other => Err(EvalError::new(format!("eval: Invalid function {}", other))),
+    }
+}
+
+fn call_lambda(lambda: &Lambda, args: Vec<Value>) -> EvalResult {
+    let local = bind_args(lambda, args)?;
+    let mut ret = Value::Nil;
+    for expr in lambda.body.iter() {
+        ret = eval_with_env(expr, &local)?;
+    }
+    Ok(ret)
+}
+
+/// A new frame under the closure's environment with `args` bound to its parameters.
+fn bind_args(lambda: &Lambda, mut args: Vec<Value>) -> Result<Env, EvalError> {
+    let arity = lambda.params.len();
+    if args.len() < arity || (lambda.rest.is_none() && args.len() > arity) {
+        return Err(EvalError::arity(
+            lambda.name.as_deref().unwrap_or("lambda"),
+            match lambda.rest {
+                Some(_) => format!("at least {}", arity),
+                None => arity.to_string(),
+            },
+            args.len(),
+        ));
+    }
+    let local = Env::with_parent(&lambda.env);
+    if let Some(rest) = &lambda.rest {
+        local.define(*rest, list(args.split_off(arity), Value::Nil));
+    }
+    for (param, arg) in lambda.params.iter().copied().zip(args) {
+        local.define(param, arg);
+    }
+    Ok(local)
+}
+
+impl FromIterator<Value> for Value {
+    /// A proper list of the values in order.
+    fn from_iter<I: IntoIterator<Item = Value>>(iter: I) -> Self {
+        list(iter.into_iter().collect(), Value::Nil)
+    }
+}
+
+/// Build the list of `items` ending in `tail` (`Nil` for a proper list).
+pub fn list(items: Vec<Value>, tail: Value) -> Value {
+    items
+        .into_iter()
+        .rev()
+        .fold(tail, |cdr, car| Value::Cons(Cons::new(car, cdr)))
+}
+
+/// Elements of the proper list `value`, the argument at 1-based `position`
+/// of `name` for type errors.
+pub fn list_to_vec(value: Value, name: &str, position: usize) -> Result<Vec<Value>, EvalError> {
+    let mut items = Vec::new();
+    let mut rest = value.clone();
+    loop {
+        match rest {
+            Value::Nil => return Ok(items),
+            Value::Cons(cons) => {
+                items.push(cons.car());
+                rest = cons.cdr();
             }
+            _ => return Err(EvalError::wrong_type(name, "list", &value, position)),
         }
     }
 }
 
-fn to_sym(token: ast::Token) -> Result<String, EvalError> {
-    match token.kind {
-        ast::TokenKind::Symbol(s) => Ok(s),
-        other => Err(EvalError(format!("Token '{:?}' is not symbol", other))),
+fn to_sym(token: &ast::Token) -> Result<Symbol, EvalError> {
+    match &token.kind {
+        ast::TokenKind::Symbol(s) => Ok(*s),
+        other => Err(EvalError::new(format!("Token '{:?}' is not symbol", other))),
+    }
+}
+
+#[cfg(test)]
+mod test_eval {
+    use crate::eval::{eval_with_env, ErrorKind, EvalError, Value};
+    use crate::{env, parse};
+    use big_s::S;
+    use codespan::Span;
+
+    fn run(srcs: &[&str]) -> Value {
+        let env = env::make_global_env();
+        let mut ret = Value::Nil;
+        for src in srcs {
+            ret = eval_with_env(&parse::parse(src).unwrap(), &env).unwrap();
+        }
+        ret
+    }
+
+    /// The error `src` fails with.
+    fn error(src: &str) -> EvalError {
+        eval_with_env(&parse::parse(src).unwrap(), &env::make_global_env()).unwrap_err()
+    }
+
+    fn truthy(srcs: &[&str]) -> bool {
+        run(srcs).is_truthy()
+    }
+
+    #[test]
+    fn call_lambda() {
+        assert_eq!(run(&["((lambda (x y) (+ x y)) 1 2)"]), Value::int(3));
+    }
+
+    #[test]
+    fn defun_recursion() {
+        assert_eq!(
+            run(&[
+                "(defun fact (n) (if (< n 1) 1 (* n (fact (- n 1)))))",
+                "(fact 5)"
+            ]),
+            Value::int(120)
+        );
+    }
+
+    #[test]
+    fn closure_captures_env() {
+        assert_eq!(
+            run(&[
+                "(defun adder (n) (lambda (x) (+ x n)))",
+                "(setq add2 (adder 2))",
+                "(add2 40)"
+            ]),
+            Value::int(42)
+        );
+    }
+
+    #[test]
+    fn setq_updates_nearest_binding() {
+        assert_eq!(
+            run(&[
+                "(setq counter 0)",
+                "(defun make-counter (n) (lambda () (setq n (+ n 1))))",
+                "(setq next (make-counter 10))",
+                "(next)",
+                "(defun bump () (setq counter (+ counter 1)))",
+                "(bump)",
+                "(+ counter (next))"
+            ]),
+            Value::int(13)
+        );
+    }
+
+    #[test]
+    fn let_forms() {
+        assert_eq!(
+            run(&[
+                "(setq x 1)",
+                "(let ((x 10) (y x)) (setq x (+ x y)))",
+                "(let* ((a x) (b (+ a 1))) (* a b))"
+            ]),
+            Value::int(2)
+        );
+        assert_eq!(
+            run(
+                &["(letrec ((even? (lambda (n) (if (= n 0) 1 (odd? (- n 1)))))
+                          (odd? (lambda (n) (if (= n 0) 0 (even? (- n 1))))))
+                   (even? 10))"]
+            ),
+            Value::int(1)
+        );
+    }
+
+    #[test]
+    fn deep_recursion() {
+        // on the test thread's stack, which is too small for `MAX_DEPTH` levels
+        let f = "(defun f (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+        assert_eq!(run(&[f, "(f 5000)"]), Value::int(5000));
+
+        let env = env::make_global_env();
+        let eval = |src| eval_with_env(&parse::parse(src).unwrap(), &env);
+        eval(f).unwrap();
+        let err = eval("(f 100000)").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::Message(S("eval: maximum recursion depth exceeded"))
+        );
+        assert!(err.report("<test>", f).contains("... and 9966 more"));
+        assert_eq!(eval("(f 10)"), Ok(Value::int(10)));
+    }
+
+    #[test]
+    fn error_span_and_stack() {
+        let src = "(defun f (x) (+ x y))\n(defun g () (f 1))\n(g)";
+        let env = env::make_global_env();
+        let err = parse::parse_program(src)
+            .unwrap()
+            .into_iter()
+            .map(|expr| eval_with_env(&expr, &env))
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::Message(S("eval: Undefined symbol y")));
+        assert_eq!(err.span, Some(Span::new(19, 20)));
+        let names = err.stack.iter().map(|f| &f.name[..]).collect::<Vec<_>>();
+        assert_eq!(names, vec!["f", "g"]);
+
+        let report = err.report("test.al", src);
+        assert!(report.contains("test.al:1:19"));
+        assert!(report.contains("in `f` called at test.al:2:13"));
+        assert!(report.contains("in `g` called at test.al:3:1"));
+    }
+
+    #[test]
+    fn booleans_and_truthiness() {
+        assert_eq!(run(&["(< 1 2)"]), Value::Bool(true));
+        assert_eq!(run(&["(= 1 2)"]), Value::Bool(false));
+        assert_eq!(run(&["(not '())"]), Value::Bool(true));
+        assert_eq!(run(&["(eq? t #t)"]), Value::Bool(true));
+        assert_eq!(run(&["(if 0 'yes 'no)"]), Value::Symbol("yes".into()));
+        assert_eq!(run(&["(if \"\" 'yes 'no)"]), Value::Symbol("yes".into()));
+        assert_eq!(run(&["(if #f 'yes 'no)"]), Value::Symbol("no".into()));
+        assert_eq!(run(&["(if nil 'yes 'no)"]), Value::Symbol("no".into()));
+        assert_eq!(run(&["'(#t #false)"]), run(&["(list #true #f)"]));
+    }
+
+    #[test]
+    fn numeric_tower() {
+        let num = |src| match run(&[src]) {
+            Value::Number(n) => n.to_string(),
+            other => panic!("not a number: {}", other),
+        };
+        assert_eq!(num("(* 9223372036854775807 2)"), "18446744073709551614");
+        assert_eq!(
+            num("(- (* 9223372036854775807 2) 9223372036854775807)"),
+            "9223372036854775807"
+        );
+        assert_eq!(num("(/ 1 3)"), "1/3");
+        assert_eq!(num("(+ 1/3 2/3)"), "1");
+        assert_eq!(num("(/ 6 3)"), "2");
+        assert_eq!(num("(+ 1 1.5)"), "2.5");
+        assert_eq!(num("(* 1/2 2.0)"), "1.0");
+        assert_eq!(num("(- 5)"), "-5");
+        assert_eq!(num("(/ 1 0.0)"), "+inf.0");
+        assert_eq!(num("#x-ff"), "-255");
+        assert_eq!(num("1e3"), "1000.0");
+        assert!(truthy(&["(= 1 1.0 2/2)"]));
+        assert!(truthy(&["(< 1/3 0.34 1)"]));
+        assert!(!truthy(&["(< 1 +nan.0)"]));
+        for op in ["=", "<", ">", "<=", ">="] {
+            assert!(truthy(&[&format!("({} 1)", op)]), "{}", op);
+            assert!(matches!(
+                error(&format!("({})", op)).kind,
+                ErrorKind::Arity { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn math_builtins() {
+        let num = |src| match run(&[src]) {
+            Value::Number(n) => n.to_string(),
+            other => panic!("not a number: {}", other),
+        };
+        assert_eq!(num("(mod -7 3)"), "2");
+        assert_eq!(num("(rem -7 3)"), "-1");
+        assert_eq!(num("(abs -9223372036854775808)"), "9223372036854775808");
+        assert_eq!(num("(min 3 1 2)"), "1");
+        assert_eq!(num("(max 1 2.0 3)"), "3.0");
+        assert_eq!(num("(expt 2 10)"), "1024");
+        assert_eq!(num("(expt 2 -1)"), "1/2");
+        assert_eq!(num("(sqrt 16)"), "4");
+        assert_eq!(num("(sqrt 2.25)"), "1.5");
+        assert_eq!(num("(floor -1/2)"), "-1");
+        assert_eq!(num("(ceiling 1.2)"), "2.0");
+        assert_eq!(num("(round 3/2)"), "2");
+        assert_eq!(num("(truncate -1.7)"), "-1.0");
+        assert_eq!(num("(sin 0)"), "0.0");
+        assert_eq!(num("(cos 0)"), "1.0");
+        assert_eq!(num("(atan 1 1)"), num("(/ (atan 1) 1.0)"));
+        assert_eq!(num("(exp 0)"), "1.0");
+        assert_eq!(num("(log 8 2)"), "3.0");
+
+        assert_eq!(error("(mod 1 0)").kind, ErrorKind::DivisionByZero);
+        assert_eq!(
+            error("(sqrt 'x)").kind,
+            ErrorKind::WrongType {
+                name: S("sqrt"),
+                expected: "number",
+                actual: "symbol",
+                position: 1
+            }
+        );
+        assert!(matches!(error("(expt 2)").kind, ErrorKind::Arity { .. }));
+        assert!(error("(expt 10 4000000000)").to_string().contains("exceed"));
+        assert!(matches!(error("(max)").kind, ErrorKind::Arity { .. }));
+        assert!(matches!(error("(log 1 2 3)").kind, ErrorKind::Arity { .. }));
+    }
+
+    #[test]
+    fn builtin_type_errors() {
+        assert_eq!(
+            error("(+ 1 \"a\")").kind,
+            ErrorKind::WrongType {
+                name: S("+"),
+                expected: "number",
+                actual: "string",
+                position: 2
+            }
+        );
+        assert_eq!(
+            error("(+ '(5 6) 1)").kind,
+            ErrorKind::WrongType {
+                name: S("+"),
+                expected: "number",
+                actual: "cons",
+                position: 1
+            }
+        );
+        assert_eq!(
+            error("(+ nil 1)").kind,
+            ErrorKind::WrongType {
+                name: S("+"),
+                expected: "number",
+                actual: "nil",
+                position: 1
+            }
+        );
+        assert_eq!(error("(/ 1 0)").kind, ErrorKind::DivisionByZero);
+        assert_eq!(
+            error("(=)").kind,
+            ErrorKind::Arity {
+                name: S("="),
+                expected: S("at least 1"),
+                given: 0
+            }
+        );
+        assert!(matches!(error("(car 1)").kind, ErrorKind::WrongType { .. }));
+    }
+
+    #[test]
+    fn macros() {
+        let when = "(defmacro when (cond &rest body) `(if ,cond (begin ,@body) '()))";
+        let unless = "(defmacro unless (cond &rest body) `(when (not ,cond) ,@body))";
+        assert_eq!(
+            run(&[
+                when,
+                unless,
+                "(setq x 1)",
+                "(when (= x 1) (setq x 2) (+ x 40))"
+            ]),
+            Value::int(42)
+        );
+        assert_eq!(run(&[when, unless, "(unless 1 2)"]), Value::Nil);
+        assert_eq!(
+            run(&[when, unless, "(macroexpand-1 '(unless a b))"]),
+            run(&["'(when (not a) b)"])
+        );
+        assert_eq!(
+            run(&[when, unless, "(macroexpand '(unless a b))"]),
+            run(&["'(if (not a) (begin b) '())"])
+        );
+        assert_eq!(
+            run(&[when, "(map macroexpand-1 '((when a b) (f x)))"]),
+            run(&["'((if a (begin b) '()) (f x))"])
+        );
+
+        let expansions = [
+            "(setq n 0)",
+            "(defmacro twice (x) (setq n (+ n 1)) `(* 2 ,x))",
+            "(defun f (x) (twice x))",
+        ];
+        assert_eq!(
+            run(&[&expansions[..], &["(f 1)", "(f 2)", "(list (f 3) n)"]].concat()),
+            run(&["'(6 1)"])
+        );
+        assert_eq!(
+            run(&[
+                &expansions[..],
+                &["(f 1)", "(defmacro twice (x) `(+ ,x ,x 1))", "(f 1)"]
+            ]
+            .concat()),
+            Value::int(3)
+        );
+    }
+
+    #[test]
+    fn macro_special_form_templates() {
+        let with = "(defmacro with (name value &rest body) `(let ((,name ,value)) ,@body))";
+        let adder = "(defmacro adder (n) `(lambda (x) (+ x ,n)))";
+        let set_to = "(defmacro set-to (s v) `(setq ,s ,v))";
+        assert_eq!(run(&[with, "(with x 2 (* x 21))"]), Value::int(42));
+        assert_eq!(run(&[adder, "((adder 2) 40)"]), Value::int(42));
+        assert_eq!(run(&[set_to, "(set-to y 42)", "y"]), Value::int(42));
+        assert_eq!(
+            run(&[with, adder, "(with n 2 ((adder n) 40))"]),
+            Value::int(42)
+        );
+        assert_eq!(
+            run(&[with, "(macroexpand '(with x 2 (f x)))"]),
+            run(&["'(let ((x 2)) (f x))"])
+        );
+        assert_eq!(
+            error("(begin (defmacro m () '(if a)) (m))").kind,
+            ErrorKind::Message(S("macro expansion: malformed `if`"))
+        );
+        assert_eq!(
+            error("(begin (defmacro m () (let ((c (list 'f 1))) (set-cdr! c c) c)) (m))").kind,
+            ErrorKind::Message(S("macro expansion: can't use circular data as code"))
+        );
+        assert_eq!(
+            error("(begin (defmacro m () (let ((c (list 'f 1))) (set-car! (cdr c) c) c)) (m))")
+                .kind,
+            ErrorKind::Message(S("macro expansion: can't use circular data as code"))
+        );
+        assert_eq!(
+            run(&[
+                "(defmacro m () (let ((c (list 1))) (set-cdr! c c) (list 'quote c)))",
+                "(car (m))"
+            ]),
+            Value::int(1)
+        );
+    }
+
+    #[test]
+    fn rest_params() {
+        assert_eq!(
+            run(&["((lambda (a &rest xs) (cons a xs)) 1 2 3)"]),
+            run(&["'(1 2 3)"])
+        );
+    }
+
+    #[test]
+    fn list_builtins() {
+        assert_eq!(run(&["(list)"]), Value::Nil);
+        assert_eq!(run(&["(list 1 2 3 4)"]), run(&["'(1 2 3 4)"]));
+        assert_eq!(
+            run(&["(append '(1 2) '() '(3) '(4 . 5))"]),
+            run(&["'(1 2 3 4 . 5)"])
+        );
+        assert_eq!(run(&["(append)"]), Value::Nil);
+        assert_eq!(run(&["(append '(1) 2)"]), run(&["'(1 . 2)"]));
+        assert_eq!(run(&["(reverse (list 1 2 3))"]), run(&["'(3 2 1)"]));
+        assert_eq!(run(&["(length '(a b c))"]), Value::int(3));
+        assert_eq!(run(&["(length '())"]), Value::int(0));
+        assert_eq!(run(&["(nth 1 '(a b c))"]), Value::Symbol("b".into()));
+        assert_eq!(run(&["(nth 3 '(a b c))"]), Value::Nil);
+        assert_eq!(run(&["(last '(a b c))"]), Value::Symbol("c".into()));
+        assert_eq!(run(&["(last '())"]), Value::Nil);
+        assert_eq!(
+            error("(append '(1) 2 '(3))").kind,
+            ErrorKind::WrongType {
+                name: S("append"),
+                expected: "list",
+                actual: "number",
+                position: 2
+            }
+        );
+        assert!(matches!(
+            error("(length '(1 . 2))").kind,
+            ErrorKind::WrongType { .. }
+        ));
+        assert!(matches!(error("(reverse)").kind, ErrorKind::Arity { .. }));
+    }
+
+    #[test]
+    fn string_builtins() {
+        let str = |s: &str| Value::Str(s.into());
+        assert_eq!(run(&[r#"(string-length "héllo")"#]), Value::int(5));
+        assert_eq!(run(&[r#"(substring "héllo" 1 3)"#]), str("él"));
+        assert_eq!(run(&[r#"(substring "hello" 2)"#]), str("llo"));
+        assert_eq!(run(&[r#"(string-append "a" "" "bc")"#]), str("abc"));
+        assert_eq!(
+            run(&[r#"(string-split "  a b\tc ")"#]),
+            run(&[r#"'("a" "b" "c")"#])
+        );
+        assert_eq!(
+            run(&[r#"(string-split "a,,b" ",")"#]),
+            run(&[r#"'("a" "" "b")"#])
+        );
+        assert_eq!(run(&[r#"(string-join '("a" "b" "c"))"#]), str("a b c"));
+        assert_eq!(run(&[r#"(string-join '("a" "b") ", ")"#]), str("a, b"));
+        assert_eq!(run(&[r#"(string-upcase "MiXed")"#]), str("MIXED"));
+        assert_eq!(run(&[r#"(string-downcase "MiXed")"#]), str("mixed"));
+        assert_eq!(run(&[r#"(string->number "1/2")"#]), run(&["1/2"]));
+        assert_eq!(run(&[r##"(string->number "#xff")"##]), Value::int(255));
+        assert_eq!(run(&[r#"(string->number "abc")"#]), Value::Bool(false));
+        assert_eq!(run(&["(number->string 255 16)"]), str("ff"));
+        assert_eq!(run(&["(number->string -5/3 2)"]), str("-101/11"));
+        assert_eq!(run(&["(number->string 1.5)"]), str("1.5"));
+        assert_eq!(run(&[r#"(string=? "a" "a" "a")"#]), Value::Bool(true));
+        assert_eq!(run(&[r#"(string<? "a" "b" "c")"#]), Value::Bool(true));
+        assert_eq!(run(&[r#"(string>? "b" "a" "a")"#]), Value::Bool(false));
+        assert_eq!(
+            run(&[r#"(format "~a is ~s~%~~" "x" "x")"#]),
+            str("x is \"x\"\n~")
+        );
+
+        assert!(error(r#"(substring "abc" 2 5)"#)
+            .to_string()
+            .contains("out of range"));
+        assert!(error(r#"(string-split "abc" "")"#)
+            .to_string()
+            .contains("empty separator"));
+        assert!(error("(number->string 1.5 2)")
+            .to_string()
+            .contains("radix"));
+        assert!(error(r#"(format "~a ~a" 1)"#)
+            .to_string()
+            .contains("too few"));
+        assert!(error(r#"(format "~q")"#)
+            .to_string()
+            .contains("unknown directive"));
+        assert!(error("(string-length 1)").to_string().contains("string"));
+    }
+
+    #[test]
+    fn chars() {
+        assert_eq!(run(&[r"#\a"]), Value::Char('a'));
+        assert_eq!(run(&[r"'(#\space #\newline #\( #\x41)"]), {
+            let chars = [' ', '\n', '(', 'A'];
+            chars.iter().copied().map(Value::Char).collect()
+        });
+        assert_eq!(run(&[r#"(string-ref "héllo" 1)"#]), Value::Char('é'));
+        assert_eq!(run(&[r#"(string->list "ab")"#]), run(&[r"'(#\a #\b)"]));
+        assert_eq!(
+            run(&[r#"(list->string (reverse (string->list "abc")))"#]),
+            Value::Str("cba".into())
+        );
+        assert_eq!(run(&[r"(char->integer #\A)"]), Value::int(65));
+        assert_eq!(run(&["(integer->char 955)"]), Value::Char('λ'));
+        assert_eq!(run(&[r"(char-alphabetic? #\λ)"]), Value::Bool(true));
+        assert_eq!(run(&[r"(char-alphabetic? #\1)"]), Value::Bool(false));
+        assert_eq!(run(&[r"(eq? #\a #\a)"]), Value::Bool(true));
+
+        assert!(error(r#"(string-ref "abc" 3)"#)
+            .to_string()
+            .contains("out of range"));
+        assert!(error(r"(list->string '(#\a 1))")
+            .to_string()
+            .contains("list of chars"));
+        assert!(error("(char->integer 1)").to_string().contains("char"));
+    }
+
+    #[test]
+    fn vectors_and_hash_tables() {
+        assert_eq!(run(&["#(1 (2) x)"]), run(&["(vector 1 '(2) 'x)"]));
+        assert_eq!(
+            run(&["#((if) 'a (let x))"]),
+            run(&["(vector '(if) ''a '(let x))"])
+        );
+        assert_eq!(
+            run(&[
+                "(defmacro second (v) (vector-ref v 1))",
+                "(second #((if) 42))"
+            ]),
+            Value::int(42)
+        );
+        assert_eq!(
+            run(&["(setq x 2)", "`#(1 ,x ,@(list 3 4))"]),
+            run(&["#(1 2 3 4)"])
+        );
+        assert_eq!(run(&["(vector-ref #(a b c) 1)"]), Value::Symbol("b".into()));
+        assert_eq!(
+            run(&["(setq v (make-vector 3 0))", "(vector-set! v 2 'x)", "v"]),
+            run(&["#(0 0 x)"])
+        );
+        assert_eq!(run(&["(vector-length (make-vector 4))"]), Value::int(4));
+        assert!(truthy(&["(setq v #(1))", "(eq? v v)"]));
+        assert!(!truthy(&["(eq? #(1) #(1))"]));
+        assert!(truthy(&["(equal? #(1 (2)) (vector 1 (list 2)))"]));
+
+        assert_eq!(
+            run(&[
+                "(setq h (make-hash-table))",
+                r#"(hash-set! h "k" 1)"#,
+                "(hash-set! h 'k 2)",
+                "(hash-set! h 1/2 3)",
+                r#"(list (hash-ref h (string-append "" "k")) (hash-ref h 'k) (hash-ref h 2/4))"#
+            ]),
+            run(&["'(1 2 3)"])
+        );
+        assert_eq!(
+            run(&[
+                "(setq h (make-hash-table))",
+                "(hash-ref h 'missing 'default)"
+            ]),
+            Value::Symbol("default".into())
+        );
+        assert_eq!(
+            run(&[
+                "(setq h (make-hash-table))",
+                "(hash-set! h 'a 1)",
+                "(hash-set! h 'b 2)",
+                "(list (hash-remove! h 'a) (hash-ref h 'a) (hash-keys h))"
+            ]),
+            run(&["'(1 nil (b))"])
+        );
+
+        assert!(error("(vector-ref #(1 2) 2)")
+            .to_string()
+            .contains("out of range"));
+        assert!(error("(make-vector 100000000000000)")
+            .to_string()
+            .contains("too long, the most is 67108864"));
+        assert!(error("(hash-set! (make-hash-table) '(1) 2)")
+            .to_string()
+            .contains("hash key"));
+        assert!(error("(hash-ref #(1) 1)")
+            .to_string()
+            .contains("hash-table"));
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(run(&["(symbol->string 'abc)"]), Value::Str("abc".into()));
+        assert!(truthy(&[r#"(eq? (string->symbol "abc") 'abc)"#]));
+        assert!(truthy(&["(setq g (gensym))", "(eq? g g)"]));
+        assert!(!truthy(&["(eq? (gensym) (gensym))"]));
+        assert!(!truthy(&[
+            "(setq g (gensym))",
+            "(eq? g (string->symbol (symbol->string g)))"
+        ]));
+        assert_eq!(
+            run(&[
+                "(defmacro swap! (a b) (let ((tmp (gensym))) (list 'let (list (list tmp a)) (list 'setq a b) (list 'setq b tmp))))",
+                "(setq tmp 1)",
+                "(setq y 2)",
+                "(swap! tmp y)",
+                "(list tmp y)"
+            ]),
+            run(&["'(2 1)"])
+        );
+    }
+
+    #[test]
+    fn higher_order_builtins() {
+        assert_eq!(
+            run(&["(map (lambda (x) (* x x)) '(1 2 3))"]),
+            run(&["'(1 4 9)"])
+        );
+        assert_eq!(run(&["(map + '(1 2 3) '(10 20))"]), run(&["'(11 22)"]));
+        assert_eq!(
+            run(&["(filter (lambda (x) (> x 1)) '(3 1 2))"]),
+            run(&["'(3 2)"])
+        );
+        assert_eq!(
+            run(&["(fold-left cons nil '(1 2))"]),
+            run(&["'((nil . 1) . 2)"])
+        );
+        assert_eq!(run(&["(fold-right cons nil '(1 2))"]), run(&["'(1 2)"]));
+        assert_eq!(run(&["(reduce + 0 '(1 2 3))"]), Value::int(6));
+        assert_eq!(run(&["(reduce + 0 '())"]), Value::int(0));
+        assert_eq!(run(&["(apply + 1 2 '(3 4))"]), Value::int(10));
+        assert_eq!(
+            run(&[
+                "(setq total 0)",
+                "(for-each (lambda (x) (setq total (+ total x))) '(1 2 3))",
+                "total"
+            ]),
+            Value::int(6)
+        );
+        assert_eq!(run(&["(sort '(3 1 2) <)"]), run(&["'(1 2 3)"]));
+        assert_eq!(
+            run(&["(sort '((b . 1) (a . 0) (c . 1) (d . 0)) (lambda (x y) (< (cdr x) (cdr y))))"]),
+            run(&["'((a . 0) (d . 0) (b . 1) (c . 1))"])
+        );
+
+        assert!(error("(sort '(1 a) <)").to_string().contains("number"));
+        assert!(error("(map car '(1))").to_string().contains("cons"));
+        assert!(error("(apply + 1)").to_string().contains("list"));
+    }
+
+    #[test]
+    fn mutable_conses() {
+        assert_eq!(
+            run(&[
+                "(setq xs (list 1 2 3))",
+                "(setq ys xs)",
+                "(set-car! (cdr xs) 'b)",
+                "(set-cdr! (cdr (cdr xs)) '(4))",
+                "ys"
+            ]),
+            run(&["'(1 b 3 4)"])
+        );
+        assert!(truthy(&["(setq xs '(1 2))", "(eq? xs xs)"]));
+        assert!(!truthy(&["(eq? '(1 2) '(1 2))"]));
+        assert!(truthy(&["(equal? '(1 2) (list 1 2))"]));
+        assert!(truthy(&["(eq? 'a 'a)"]));
+        assert!(!truthy(&["(equal? '(1 2) '(1 2 3))"]));
+        assert!(truthy(&[
+            "(setq xs (list 1 2))",
+            "(set-cdr! (cdr xs) xs)",
+            "(setq ys (list 1 2 1 2))",
+            "(set-cdr! (cdr (cdr (cdr ys))) ys)",
+            "(equal? xs ys)"
+        ]));
+        assert!(!truthy(&[
+            "(setq xs (list 1 2))",
+            "(set-cdr! (cdr xs) xs)",
+            "(setq ys (list 1 3))",
+            "(set-cdr! (cdr ys) ys)",
+            "(equal? xs ys)"
+        ]));
+
+        let long = || (0..1_000_000).map(Value::int).collect::<Value>();
+        assert_eq!(long(), long());
+    }
+
+    #[test]
+    fn drop_deeply_nested_data() {
+        use crate::eval::{list, Cons, HashTable, Key, Vector};
+        let mut value = Value::Nil;
+        for i in 0..1_000_000 {
+            value = match i % 3 {
+                0 => list(vec![value], Value::Nil),
+                1 => Value::Vector(Vector::new(vec![value])),
+                _ => {
+                    let table = HashTable::new();
+                    table.insert(Key::Char('k'), value);
+                    Value::HashTable(table)
+                }
+            };
+        }
+        let shared = Cons::new(value, Value::Nil);
+        let nested = list(vec![Value::Cons(shared.clone())], Value::Nil);
+        drop(nested);
+        assert!(matches!(shared.car(), Value::Cons(_)));
+        drop(shared);
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_stack() {
+        assert_eq!(
+            run(&[
+                "(defun count (n acc) (if (= n 0) acc (begin (setq acc (+ acc 1)) (count (- n 1) acc))))",
+                "(count 1000000 0)"
+            ]),
+            Value::int(1000000)
+        );
+        assert_eq!(
+            run(&[
+                "(defun even? (n) (if (= n 0) 1 (odd? (- n 1))))",
+                "(defun odd? (n) (let ((m (- n 1))) (if (< m 0) '() (even? m))))",
+                "(even? 100001)"
+            ]),
+            Value::Nil
+        );
     }
 }
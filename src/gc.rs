@@ -0,0 +1,274 @@
+//! Cycle collector for the reference-counted heap.
+//!
+//! Cons cells, vectors, hash tables, environment frames and closures are
+//! `Rc`s, which free everything except cycles: a circular list, or a closure
+//! stored in the frame it closes over. Each of them is registered here when allocated, and
+//! `collect` marks from the roots and breaks the cycles it can't reach.
+//!
+//! The roots are the objects held from outside the heap: the environment
+//! chain and values on the evaluation stack, which Rust code holds as strong
+//! references. An object whose strong count exceeds the references the heap
+//! itself holds to it is therefore a root, so no explicit root set is needed.
+
+use super::eval::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// A heap object that can refer to other heap objects.
+pub trait Trace {
+    /// Call `visit` with the address of every heap object `self` refers to.
+    fn trace(&self, visit: &mut dyn FnMut(*const ()));
+
+    /// Drop the references `self` holds, to break a cycle it is part of.
+    fn clear(&self);
+}
+
+/// Visit the heap object `value` refers to, if any.
+pub fn trace_value(value: &Value, visit: &mut dyn FnMut(*const ())) {
+    match value {
+        Value::Cons(cons) => visit(cons.addr()),
+        Value::Vector(vector) => visit(vector.addr()),
+        Value::HashTable(table) => visit(table.addr()),
+        Value::Closure(lambda) | Value::Macro(lambda) => visit(Rc::as_ptr(lambda) as *const ()),
+        _ => (),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Stats {
+    /// registered objects still alive
+    pub live: usize,
+    /// approximate size of the live objects
+    pub bytes: usize,
+    pub collections: usize,
+    /// objects freed by all collections so far
+    pub freed: usize,
+}
+
+/// Allocations between automatic collections, at least.
+const MIN_THRESHOLD: usize = 10_000;
+
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    /// objects registered since the last collection
+    allocated: usize,
+    threshold: usize,
+    /// registry size at which dead entries are dropped; a `Weak` keeps the
+    /// memory of its object allocated
+    prune_at: usize,
+    collections: usize,
+    freed: usize,
+}
+
+impl Heap {
+    fn prune(&mut self) {
+        self.objects.retain(|object| object.strong_count() > 0);
+        self.prune_at = MIN_THRESHOLD.max(self.objects.len() * 2);
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: Vec::new(),
+        allocated: 0,
+        threshold: MIN_THRESHOLD,
+        prune_at: MIN_THRESHOLD,
+        collections: 0,
+        freed: 0,
+    });
+}
+
+/// Track a newly allocated object.
+pub fn register<T: Trace + 'static>(object: &Rc<T>) {
+    let weak: Weak<T> = Rc::downgrade(object);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(weak as Weak<dyn Trace>);
+        heap.allocated += 1;
+        if heap.objects.len() >= heap.prune_at {
+            heap.prune();
+        }
+    })
+}
+
+/// Collect if enough has been allocated since the last collection. Called by
+/// the evaluator between steps, where it holds no borrows of the heap.
+pub fn maybe_collect() {
+    if HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated >= heap.threshold
+    }) {
+        collect();
+    }
+}
+
+/// Free the objects that are unreachable from the roots, and return how many.
+pub fn collect() -> usize {
+    let objects = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        heap.objects
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>()
+    });
+    let index = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (Rc::as_ptr(object) as *const (), i))
+        .collect::<HashMap<_, _>>();
+
+    // references from outside the heap; the upgrade above holds one more
+    let mut external = objects
+        .iter()
+        .map(|object| Rc::strong_count(object) - 1)
+        .collect::<Vec<_>>();
+    for object in &objects {
+        object.trace(&mut |addr| {
+            if let Some(&i) = index.get(&addr) {
+                external[i] -= 1;
+            }
+        });
+    }
+
+    let mut marked = vec![false; objects.len()];
+    let mut stack = (0..objects.len())
+        .filter(|&i| external[i] > 0)
+        .collect::<Vec<_>>();
+    while let Some(i) = stack.pop() {
+        if marked[i] {
+            continue;
+        }
+        marked[i] = true;
+        objects[i].trace(&mut |addr| {
+            if let Some(&j) = index.get(&addr) {
+                if !marked[j] {
+                    stack.push(j);
+                }
+            }
+        });
+    }
+
+    let mut freed = 0;
+    for (object, marked) in objects.iter().zip(marked) {
+        if !marked {
+            object.clear();
+            freed += 1;
+        }
+    }
+    let live = objects.len() - freed;
+    drop(objects);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated = 0;
+        heap.threshold = MIN_THRESHOLD.max(live * 2);
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+    freed
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let live = heap
+            .objects
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        Stats {
+            live: live.len(),
+            bytes: live
+                .iter()
+                .map(|object| std::mem::size_of_val(&**object) + 2 * std::mem::size_of::<usize>())
+                .sum(),
+            collections: heap.collections,
+            freed: heap.freed,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test_gc {
+    use crate::env::{self, Env};
+    use crate::eval::{eval_with_env, Cons, Value};
+    use crate::{gc, parse};
+
+    fn run(env: &Env, src: &str) -> Value {
+        eval_with_env(&parse::parse(src).unwrap(), env).unwrap()
+    }
+
+    /// Evaluate `srcs` in a new global environment, then check that a
+    /// collection, while the environment is still in use, frees `freed`
+    /// objects and that the stats count them. Returns the environment.
+    fn assert_collects(srcs: &[&str], freed: usize) -> Env {
+        let env = env::make_global_env();
+        for src in srcs {
+            run(&env, src);
+        }
+        let before = gc::stats();
+        assert_eq!(gc::collect(), freed);
+        let after = gc::stats();
+        assert_eq!(after.live, before.live - freed);
+        assert_eq!(after.collections, before.collections + 1);
+        assert_eq!(after.freed, before.freed + freed);
+        env
+    }
+
+    #[test]
+    fn collect_cycles() {
+        // the let frames are freed on exit, only the lists leak
+        assert_collects(&["(let ((xs (list 1))) (set-cdr! xs xs))"], 1);
+        assert_collects(&["(let ((xs (list 1 2))) (set-car! (cdr xs) xs))"], 2);
+        let env = assert_collects(&["(setq xs (list 1))", "(set-cdr! xs xs)"], 0);
+        assert_eq!(run(&env, "(car xs)"), Value::int(1));
+        assert_eq!(run(&env, "(eq? (cdr xs) xs)"), Value::Bool(true));
+
+        // a reference from Rust is a root too
+        let held = Cons::new(Value::int(1), Value::Nil);
+        held.set_cdr(Value::Cons(held.clone()));
+        assert_eq!(gc::collect(), 0);
+        assert_eq!(held.cdr(), Value::Cons(held.clone()));
+    }
+
+    #[test]
+    fn collect_vector_and_table_cycles() {
+        assert_collects(&["(let ((v (make-vector 1))) (vector-set! v 0 v))"], 1);
+        assert_collects(&["(let ((h (make-hash-table))) (hash-set! h 'self h))"], 1);
+        assert_collects(
+            &["(let ((v (make-vector 1)) (xs (list 1))) (vector-set! v 0 xs) (set-car! xs v))"],
+            2,
+        );
+        assert_collects(
+            &["(let ((h (make-hash-table)) (xs (list 1))) (hash-set! h 'xs xs) (set-cdr! xs (list h)))"],
+            3,
+        );
+    }
+
+    #[test]
+    fn collect_closures_in_their_own_env() {
+        // the let frame, the closure and the frame of the call to `leak`
+        let env = assert_collects(
+            &[
+                "(defun leak () (let ((f nil)) (setq f (lambda () f)) 1))",
+                "(leak)",
+            ],
+            3,
+        );
+        assert_eq!(run(&env, "(leak)"), Value::int(1));
+    }
+
+    #[test]
+    fn collect_automatically() {
+        let env = env::make_global_env();
+        run(
+            &env,
+            "(defun ring (n) (let ((xs (list n))) (set-cdr! xs xs) (if (= n 0) 0 (ring (- n 1)))))",
+        );
+        run(&env, "(ring 100000)");
+        assert!(gc::stats().collections > 0);
+        assert!(gc::stats().live < 100000);
+    }
+}
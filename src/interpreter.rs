@@ -0,0 +1,362 @@
+//! The interface for embedding alone in a Rust program.
+
+use super::env::{make_global_env, Env};
+use super::eval::{apply, eval_with_env, EvalError, EvalResult, Value};
+use super::native::{IntoNative, IntoValue};
+use super::parse::{self, ParseError};
+use super::symbol::Symbol;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// An interpreter with its own global environment, input and output. It
+/// never touches stdin or stdout or exits the process: input is empty unless
+/// supplied with `set_input`, output is kept until `take_output` unless sent
+/// elsewhere with `set_output`, and `(exit)` is an error of kind
+/// `ErrorKind::Exit`. Evaluation runs on the calling thread, whatever its
+/// stack size: deep recursion continues on stack allocated as needed (see
+/// `stack`) and fails with an error past `eval::MAX_DEPTH`.
+///
+/// Interpreters on the same thread share that thread's symbol table and
+/// heap (see `symbol` and `gc`): a symbol means the same in all of them,
+/// `gensym` never returns a symbol any of them has seen, and `(gc)` in one
+/// collects the garbage of all of them, which `(gc-stats)` counts together.
+/// Evaluations nested across them, e.g. through a host function, count
+/// toward one `eval::MAX_DEPTH`. Their environments, input and output are
+/// their own.
+pub struct Interpreter {
+    env: Env,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+/// An error from `Interpreter::eval_str` or `Interpreter::eval_file`.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(ParseError),
+    Eval(EvalError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "error: {}", e),
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        Error::Eval(e)
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let env = make_global_env();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        env.set_output(output.clone());
+        env.set_input(Rc::new(RefCell::new(std::io::empty())));
+        Interpreter { env, output }
+    }
+
+    /// The global environment.
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Evaluate the forms in `source` in order and return the value of the
+    /// last, or `nil` if there are none.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Error> {
+        let mut ret = Value::Nil;
+        for expr in parse::parse_program(source)? {
+            ret = eval_with_env(&expr, &self.env)?;
+        }
+        Ok(ret)
+    }
+
+    /// Like `eval_str`, with the contents of the file at `path`.
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<Value, Error> {
+        let source = std::fs::read_to_string(path)?;
+        self.eval_str(&source)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.env.get(Symbol::intern(name))
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) -> Result<(), EvalError> {
+        self.env.define(Symbol::intern(name), value.into_value()?);
+        Ok(())
+    }
+
+    /// Call the global function `name` with `args`.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> EvalResult {
+        let func = self
+            .get_global(name)
+            .ok_or_else(|| EvalError::new(format!("eval: Invalid function {}", name)))?;
+        apply(func, args, &self.env)
+    }
+
+    /// Define `name` as a host function: a closure taking `&[Value]`, or one
+    /// whose arguments implement `FromValue` and whose result implements
+    /// `IntoValue`, e.g. `|a: i64, b: i64| a + b`.
+    pub fn register_fn<Args>(&mut self, name: &str, func: impl IntoNative<Args>) {
+        let native = func.into_native(name);
+        self.env
+            .define(Symbol::intern(name), Value::Native(Rc::new(native)));
+    }
+
+    /// The output written since the last call, if it is kept.
+    pub fn take_output(&mut self) -> String {
+        let output = std::mem::take(&mut *self.output.borrow_mut());
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// Send output to `writer` instead of keeping it.
+    pub fn set_output(&mut self, writer: impl Write + 'static) {
+        self.env.set_output(Rc::new(RefCell::new(writer)));
+    }
+
+    /// Read input, e.g. for `read-line`, from `reader`.
+    pub fn set_input(&mut self, reader: impl BufRead + 'static) {
+        self.env.set_input(Rc::new(RefCell::new(reader)));
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod test_interpreter {
+    use crate::eval::{ErrorKind, EvalError, Value};
+    use crate::interpreter::{Error, Interpreter};
+
+    fn run(interp: &mut Interpreter, src: &str) -> Result<Value, EvalError> {
+        match interp.eval_str(src) {
+            Ok(value) => Ok(value),
+            Err(Error::Eval(e)) => Err(e),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn register_typed_fns() {
+        let mut interp = Interpreter::new();
+        interp.register_fn("add", |a: i64, b: i64| a + b);
+        interp.register_fn("greet", |name: String| format!("hello, {}", name));
+        interp.register_fn("halve", |xs: Vec<f64>| {
+            xs.into_iter().map(|x| x / 2.0).collect::<Vec<_>>()
+        });
+        interp.register_fn("answer", || 42);
+        interp.register_fn("checked-div", |a: i64, b: i64| {
+            a.checked_div(b)
+                .ok_or_else(|| EvalError::new("checked-div: division by zero"))
+        });
+
+        assert_eq!(run(&mut interp, "(add 1 2)"), Ok(Value::int(3)));
+        assert_eq!(
+            run(&mut interp, r#"(greet "world")"#),
+            Ok(Value::Str("hello, world".into()))
+        );
+        assert_eq!(
+            run(&mut interp, "(halve '(1 3))"),
+            run(&mut interp, "'(0.5 1.5)")
+        );
+        assert_eq!(run(&mut interp, "(answer)"), Ok(Value::int(42)));
+        assert_eq!(
+            run(&mut interp, "(map add '(1 2) '(10 20))"),
+            run(&mut interp, "'(11 22)")
+        );
+        assert!(run(&mut interp, "(checked-div 1 0)")
+            .unwrap_err()
+            .to_string()
+            .contains("division by zero"));
+        assert!(run(&mut interp, "(add 1)")
+            .unwrap_err()
+            .to_string()
+            .contains("add expects 2, got 1"));
+        assert!(run(&mut interp, r#"(add 1 "2")"#)
+            .unwrap_err()
+            .to_string()
+            .contains("add"));
+    }
+
+    #[test]
+    fn register_variadic_fns_with_state() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut interp = Interpreter::new();
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        interp.register_fn("count-args", move |args: &[Value]| {
+            counter.set(counter.get() + 1);
+            Ok(Value::int(args.len() as i64))
+        });
+        assert_eq!(run(&mut interp, "(count-args 1 2 3)"), Ok(Value::int(3)));
+        assert_eq!(run(&mut interp, "(count-args)"), Ok(Value::int(0)));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn eval_and_globals() {
+        let mut interp = Interpreter::new();
+        assert_eq!(run(&mut interp, ""), Ok(Value::Nil));
+        assert_eq!(
+            run(&mut interp, "(defun sq (x) (* x x)) (setq n 3) (sq n)"),
+            Ok(Value::int(9))
+        );
+        assert_eq!(interp.get_global("n"), Some(Value::int(3)));
+        assert_eq!(interp.get_global("missing"), None);
+        interp.set_global("greeting", "hi").unwrap();
+        interp.set_global("xs", vec![1, 2]).unwrap();
+        assert_eq!(
+            run(&mut interp, "(cons greeting xs)"),
+            run(&mut interp, r#"'("hi" 1 2)"#)
+        );
+        assert_eq!(interp.call("sq", vec![Value::int(4)]), Ok(Value::int(16)));
+        assert_eq!(interp.call("+", vec![Value::int(1)]), Ok(Value::int(1)));
+        assert!(interp.call("missing", Vec::new()).is_err());
+        assert!(matches!(interp.eval_str("(+ 1"), Err(Error::Parse(_))));
+        assert!(matches!(
+            interp.eval_file("/nonexistent/file.lisp"),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn instances_are_isolated() {
+        let mut a = Interpreter::new();
+        let mut b = Interpreter::new();
+        run(&mut a, "(setq x 1) (defun f () 'a)").unwrap();
+        assert_eq!(b.get_global("x"), None);
+        assert!(run(&mut b, "(f)").is_err());
+        run(&mut b, "(setq x 2)").unwrap();
+        assert_eq!(a.get_global("x"), Some(Value::int(1)));
+    }
+
+    #[test]
+    fn instances_on_a_thread_share_symbols_and_heap() {
+        let mut a = Interpreter::new();
+        let mut b = Interpreter::new();
+        let sym = run(&mut a, "'shared").unwrap();
+        assert_eq!(run(&mut b, "'shared"), Ok(sym));
+        let gensym = run(&mut a, "(gensym)").unwrap();
+        b.set_global("g", gensym).unwrap();
+        assert_eq!(run(&mut b, "(eq? g (gensym))"), Ok(Value::Bool(false)));
+
+        let collections = |interp: &mut Interpreter| run(interp, "(cdr (nth 2 (gc-stats)))");
+        let before = collections(&mut a).unwrap();
+        run(
+            &mut a,
+            "(setq xs (list 1 2)) (set-cdr! (cdr xs) xs) (setq xs nil)",
+        )
+        .unwrap();
+        assert_eq!(run(&mut b, "(gc)"), Ok(Value::int(2)));
+        assert_eq!(
+            collections(&mut a),
+            run(&mut a, &format!("(+ {} 1)", before))
+        );
+    }
+
+    #[test]
+    fn output_and_exit_stay_inside() {
+        let mut interp = Interpreter::new();
+        run(
+            &mut interp,
+            r#"(print 1) (display "a") (write "b") (newline)"#,
+        )
+        .unwrap();
+        assert_eq!(interp.take_output(), "1\na\"b\"\n");
+        assert_eq!(interp.take_output(), "");
+
+        let e = run(&mut interp, "(exit 3)").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Exit(3));
+    }
+
+    #[test]
+    fn deep_programs_on_a_default_thread() {
+        std::thread::spawn(|| {
+            let mut interp = Interpreter::new();
+            let f = "(defun f (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+            run(&mut interp, f).unwrap();
+            assert_eq!(run(&mut interp, "(f 900)"), Ok(Value::int(900)));
+            assert!(run(&mut interp, "(f 100000)")
+                .unwrap_err()
+                .to_string()
+                .contains("maximum recursion depth exceeded"));
+
+            let depth = 5000;
+            let nested = format!("{}1{}", "(list ".repeat(depth), ")".repeat(depth));
+            let value = run(&mut interp, &nested).unwrap();
+            assert_eq!(
+                value.to_string(),
+                format!("{}1{}", "(".repeat(depth), ")".repeat(depth))
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn ports() {
+        let mut interp = Interpreter::new();
+        interp.set_input(std::io::Cursor::new("first\r\nsecond"));
+        assert_eq!(
+            run(&mut interp, "(list (read-line) (read-line) (read-line))"),
+            run(&mut interp, r#"'("first" "second" nil)"#)
+        );
+        assert_eq!(
+            run(
+                &mut interp,
+                r#"(with-output-to-string (lambda () (display "a") (write-string "b") (print 1)))"#
+            ),
+            Ok(Value::Str("ab1\n".into()))
+        );
+        assert_eq!(
+            run(
+                &mut interp,
+                r#"(with-output-to-string (lambda () (write-string "x" (current-output-port))))"#
+            ),
+            Ok(Value::Str("x".into()))
+        );
+        run(&mut interp, r#"(setq out (current-output-port))"#).unwrap();
+        run(
+            &mut interp,
+            r#"(with-output-to-string (lambda () (write-string "outer" out)))"#,
+        )
+        .unwrap();
+        assert_eq!(interp.take_output(), "outer");
+        assert!(run(&mut interp, "(with-output-to-string (lambda () (car 1)))").is_err());
+        run(&mut interp, r#"(display "restored")"#).unwrap();
+        assert_eq!(interp.take_output(), "restored");
+        assert!(
+            run(&mut interp, r#"(write-string "x" (current-input-port))"#)
+                .unwrap_err()
+                .to_string()
+                .contains("output port")
+        );
+    }
+}
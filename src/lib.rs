@@ -1,4 +1,15 @@
 pub mod ast;
 pub mod env;
 pub mod eval;
+pub mod gc;
+pub mod interpreter;
+pub mod native;
+pub mod number;
 pub mod parse;
+pub mod print;
+pub mod quote;
+pub mod report;
+pub mod stack;
+pub mod symbol;
+
+pub use interpreter::Interpreter;
@@ -1,10 +1,7 @@
 #[macro_use]
 extern crate clap;
 
-mod ast;
-mod env;
-mod eval;
-mod parse;
+use alone::{ast, env, eval, parse};
 
 fn main() {
     let matches = clap_app!(alone =>
@@ -16,36 +13,88 @@ fn main() {
     )
     .get_matches();
 
-    if matches.is_present("file") {
-        let source =
-            std::fs::read_to_string(matches.value_of("file").unwrap()).expect("Can't read file");
-        print(eval::eval(parse::parse(&source)));
-    } else {
-        if !matches.is_present("quiet") {
-            println!("{}", crate_description!());
+    match matches.value_of("file") {
+        Some(path) => run_file(path),
+        None => repl(matches.is_present("quiet")),
+    }
+}
+
+fn repl(quiet: bool) {
+    if !quiet {
+        println!("{}", crate_description!());
+    }
+    let env = env::make_global_env();
+    let mut history = String::new();
+    loop {
+        match read(&env, &mut history) {
+            Ok(program) => {
+                for expr in program {
+                    let result = eval::eval_with_env(&expr, &env);
+                    let failed = result.is_err();
+                    print(&env, result, &history);
+                    if failed {
+                        break;
+                    }
+                }
+            }
+            Err(report) => eprint!("{}", report),
         }
-        let mut env = env::make_global_env();
-        loop {
-            print(eval::eval_with_env(read(), &mut env))
+    }
+}
+
+fn run_file(path: &str) {
+    let source = std::fs::read_to_string(path).expect("Can't read file");
+    let program = match parse::parse_program(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprint!("{}", e.report(path, &source));
+            std::process::exit(1);
         }
+    };
+    let env = env::make_global_env();
+    for expr in program {
+        if let Err(e) = eval::eval_with_env(&expr, &env) {
+            exit_on_request(&e);
+            eprint!("{}", e.report(path, &source));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit if `e` came from a call to `exit`.
+fn exit_on_request(e: &eval::EvalError) {
+    if let eval::ErrorKind::Exit(code) = e.kind {
+        std::io::Write::flush(&mut std::io::stdout()).expect("Can't flush stdout");
+        std::process::exit(code);
     }
 }
 
-fn read() -> ast::Expr {
-    use std::io::prelude::*;
-    let mut buf = String::new();
+/// Read one line into the session `history` and parse the forms on it, so
+/// spans of functions defined on earlier lines still point at their source.
+/// A blank line or the end of input ends the session.
+fn read(env: &env::Env, history: &mut String) -> Result<Vec<ast::Expr>, String> {
+    let offset = history.len();
 
-    print!("Alone > ");
-    std::io::stdout().flush().expect("Can't flush stdout");
-    std::io::stdin()
-        .read_line(&mut buf)
-        .expect("Can't read from stdin");
-    parse::parse(&buf)
+    let output = env.output();
+    write!(output.borrow_mut(), "Alone > ").expect("Can't write prompt");
+    output.borrow_mut().flush().expect("Can't flush output");
+    env.input()
+        .borrow_mut()
+        .read_line(history)
+        .expect("Can't read input");
+    if history[offset..].trim().is_empty() {
+        println!("bye");
+        std::process::exit(0)
+    }
+    parse::parse_program_at(history, offset).map_err(|e| e.report("<repl>", history))
 }
 
-fn print(result: eval::EvalResult) {
+fn print(env: &env::Env, result: eval::EvalResult, history: &str) {
     match result {
-        Ok(value) => println!("{}", value),
-        Err(e) => eprintln!("Error! {}", e),
+        Ok(value) => writeln!(env.output().borrow_mut(), "{}", value).expect("Can't write result"),
+        Err(e) => {
+            exit_on_request(&e);
+            eprint!("{}", e.report("<repl>", history))
+        }
     }
 }
@@ -0,0 +1,305 @@
+//! Host functions: Rust closures callable from Lisp.
+//!
+//! `IntoNative` wraps a closure taking `FromValue` arguments and returning an
+//! `IntoValue` in a `Native`, which checks the arity and converts the
+//! arguments, so `|a: i64, b: i64| a + b` can be registered as is.
+
+use super::eval::{list, list_to_vec, EvalError, EvalResult, Value};
+use super::number::Number;
+use super::symbol::Symbol;
+use std::fmt;
+
+/// How many arguments a function takes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, given: usize) -> bool {
+        match self {
+            Arity::Exactly(n) => given == n,
+            Arity::AtLeast(n) => given >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+type HostFn = dyn Fn(&[Value]) -> EvalResult;
+
+/// A named host function with its arity.
+pub struct Native {
+    pub name: String,
+    pub arity: Arity,
+    func: Box<HostFn>,
+}
+
+impl Native {
+    pub fn new(
+        name: impl Into<String>,
+        arity: Arity,
+        func: impl Fn(&[Value]) -> EvalResult + 'static,
+    ) -> Self {
+        Native {
+            name: name.into(),
+            arity,
+            func: Box::new(func),
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> EvalResult {
+        if !self.arity.accepts(args.len()) {
+            return Err(EvalError::arity(
+                &self.name,
+                self.arity.to_string(),
+                args.len(),
+            ));
+        }
+        (self.func)(args)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+/// A Rust type an argument can be converted to.
+pub trait FromValue: Sized {
+    /// `name` and the 1-based `position` describe the argument on a type error.
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError>;
+}
+
+/// A Rust type a result can be converted from.
+pub trait IntoValue {
+    fn into_value(self) -> EvalResult;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value, _: &str, _: usize) -> Result<Self, EvalError> {
+        Ok(value)
+    }
+}
+
+impl FromValue for Number {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        value.into_num(name, position)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        value.into_int(name, position)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        Ok(value.into_num(name, position)?.to_f64())
+    }
+}
+
+/// Any value, by truthiness.
+impl FromValue for bool {
+    fn from_value(value: Value, _: &str, _: usize) -> Result<Self, EvalError> {
+        Ok(value.is_truthy())
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        match value {
+            Value::Str(s) => Ok(s),
+            other => Err(EvalError::wrong_type(name, "string", &other, position)),
+        }
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        match value {
+            Value::Char(c) => Ok(c),
+            other => Err(EvalError::wrong_type(name, "char", &other, position)),
+        }
+    }
+}
+
+impl FromValue for Symbol {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        match value {
+            Value::Symbol(s) => Ok(s),
+            other => Err(EvalError::wrong_type(name, "symbol", &other, position)),
+        }
+    }
+}
+
+/// A proper list.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        list_to_vec(value, name, position)?
+            .into_iter()
+            .map(|item| T::from_value(item, name, position))
+            .collect()
+    }
+}
+
+/// `nil` is `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value, name: &str, position: usize) -> Result<Self, EvalError> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_value(value, name, position).map(Some),
+        }
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> EvalResult {
+        Ok(self)
+    }
+}
+
+impl IntoValue for Number {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Number(self))
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::int(self))
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Number(Number::Float(self)))
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Bool(self))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Str(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Str(self.into()))
+    }
+}
+
+impl IntoValue for char {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Char(self))
+    }
+}
+
+impl IntoValue for Symbol {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Symbol(self))
+    }
+}
+
+/// `nil`.
+impl IntoValue for () {
+    fn into_value(self) -> EvalResult {
+        Ok(Value::Nil)
+    }
+}
+
+/// A proper list.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> EvalResult {
+        let items = self
+            .into_iter()
+            .map(IntoValue::into_value)
+            .collect::<Result<_, _>>()?;
+        Ok(list(items, Value::Nil))
+    }
+}
+
+/// `None` is `nil`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> EvalResult {
+        self.map_or(Ok(Value::Nil), IntoValue::into_value)
+    }
+}
+
+/// An error is raised in Lisp.
+impl<T: IntoValue> IntoValue for Result<T, EvalError> {
+    fn into_value(self) -> EvalResult {
+        self?.into_value()
+    }
+}
+
+/// A closure that can be wrapped in a `Native`. `Args` is the tuple of its
+/// argument types, or `Variadic` for a closure taking the raw arguments.
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> Native;
+}
+
+/// The `Args` of a closure taking `&[Value]`, which accepts any number of
+/// arguments.
+pub struct Variadic;
+
+impl<F> IntoNative<Variadic> for F
+where
+    F: Fn(&[Value]) -> EvalResult + 'static,
+{
+    fn into_native(self, name: &str) -> Native {
+        Native::new(name, Arity::AtLeast(0), self)
+    }
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident: $ty:ident),*) => {
+        impl<F, R, $($ty),*> IntoNative<($($ty,)*)> for F
+        where
+            F: Fn($($ty),*) -> R + 'static,
+            R: IntoValue,
+            $($ty: FromValue,)*
+        {
+            #[allow(unused_variables, unused_mut)]
+            fn into_native(self, name: &str) -> Native {
+                let arity = <[&str]>::len(&[$(stringify!($arg)),*]);
+                let fn_name = name.to_string();
+                Native::new(name, Arity::Exactly(arity), move |args: &[Value]| {
+                    let mut args = args.iter().cloned().zip(1..);
+                    $(
+                        let (value, position) = args.next().unwrap();
+                        let $arg = $ty::from_value(value, &fn_name, position)?;
+                    )*
+                    self($($arg),*).into_value()
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(a: A);
+impl_into_native!(a: A, b: B);
+impl_into_native!(a: A, b: B, c: C);
+impl_into_native!(a: A, b: B, c: C, d: D);
+impl_into_native!(a: A, b: B, c: C, d: D, e: E);
+impl_into_native!(a: A, b: B, c: C, d: D, e: E, f: G);
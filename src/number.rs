@@ -0,0 +1,517 @@
+//! The numeric tower: fixnums that overflow into bignums, exact rationals
+//! and floats. Results are normalised, so a bignum always needs more than 64
+//! bits and a rational is never a whole number; mixed arithmetic converts to
+//! the more general operand, ending at `Float`.
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Pow, Signed, ToPrimitive};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Number {
+    Int(i64),
+    Big(BigInt),
+    Ratio(BigRational),
+    Float(f64),
+}
+
+use Number::*;
+
+/// Most bits an exact power may have, some 2MB; `expt` past it would take
+/// too long and too much memory to be of use.
+pub const MAX_EXPT_BITS: u64 = 1 << 24;
+
+/// Both operands of a binary operation, converted to the same level.
+enum Pair {
+    Int(i64, i64),
+    Big(BigInt, BigInt),
+    Ratio(BigRational, BigRational),
+    Float(f64, f64),
+}
+
+impl Number {
+    fn pair(&self, other: &Number) -> Pair {
+        match (self, other) {
+            (Int(a), Int(b)) => Pair::Int(*a, *b),
+            (Float(_), _) | (_, Float(_)) => Pair::Float(self.to_f64(), other.to_f64()),
+            (Ratio(_), _) | (_, Ratio(_)) => Pair::Ratio(self.to_ratio(), other.to_ratio()),
+            _ => Pair::Big(self.to_big(), other.to_big()),
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Int(n) => BigInt::from(*n),
+            Big(n) => n.clone(),
+            _ => unreachable!("not an integer: {}", self),
+        }
+    }
+
+    fn to_ratio(&self) -> BigRational {
+        match self {
+            Ratio(r) => r.clone(),
+            n => BigRational::from_integer(n.to_big()),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Int(n) => *n as f64,
+            Big(n) => n.to_f64().unwrap_or(f64::NAN),
+            Ratio(r) => r.to_f64().unwrap_or(f64::NAN),
+            Float(x) => *x,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Float(_))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Int(n) => *n == 0,
+            Float(x) => *x == 0.0,
+            _ => false,
+        }
+    }
+
+    pub fn add(&self, other: &Number) -> Number {
+        match self.pair(other) {
+            Pair::Int(a, b) => a
+                .checked_add(b)
+                .map_or_else(|| Number::from(BigInt::from(a) + b), Int),
+            Pair::Big(a, b) => Number::from(a + b),
+            Pair::Ratio(a, b) => Number::from(a + b),
+            Pair::Float(a, b) => Float(a + b),
+        }
+    }
+
+    pub fn sub(&self, other: &Number) -> Number {
+        match self.pair(other) {
+            Pair::Int(a, b) => a
+                .checked_sub(b)
+                .map_or_else(|| Number::from(BigInt::from(a) - b), Int),
+            Pair::Big(a, b) => Number::from(a - b),
+            Pair::Ratio(a, b) => Number::from(a - b),
+            Pair::Float(a, b) => Float(a - b),
+        }
+    }
+
+    pub fn mul(&self, other: &Number) -> Number {
+        match self.pair(other) {
+            Pair::Int(a, b) => a
+                .checked_mul(b)
+                .map_or_else(|| Number::from(BigInt::from(a) * b), Int),
+            Pair::Big(a, b) => Number::from(a * b),
+            Pair::Ratio(a, b) => Number::from(a * b),
+            Pair::Float(a, b) => Float(a * b),
+        }
+    }
+
+    /// Exact division yields a rational unless it comes out whole; `None`
+    /// on an exact division by zero. Float division follows IEEE 754.
+    pub fn div(&self, other: &Number) -> Option<Number> {
+        Some(match self.pair(other) {
+            Pair::Float(a, b) => Float(a / b),
+            _ if other.is_exact() && other.is_zero() => return None,
+            Pair::Int(a, b) if a.checked_rem(b) == Some(0) => Int(a / b),
+            pair => {
+                let (a, b) = match pair {
+                    Pair::Int(a, b) => (BigInt::from(a), BigInt::from(b)),
+                    Pair::Big(a, b) => (a, b),
+                    Pair::Ratio(a, b) => return Some(Number::from(a / b)),
+                    Pair::Float(..) => unreachable!(),
+                };
+                Number::from(BigRational::new(a, b))
+            }
+        })
+    }
+
+    pub fn neg(&self) -> Number {
+        Int(0).sub(self)
+    }
+
+    pub fn abs(&self) -> Number {
+        match self {
+            Int(n) => n
+                .checked_abs()
+                .map_or_else(|| Number::from(BigInt::from(*n).abs()), Int),
+            Big(n) => Big(n.abs()),
+            Ratio(r) => Ratio(r.abs()),
+            Float(x) => Float(x.abs()),
+        }
+    }
+
+    fn is_negative(&self) -> bool {
+        self.compare(&Int(0)) == Some(Ordering::Less)
+    }
+
+    /// Round towards negative infinity; floats stay floats.
+    pub fn floor(&self) -> Number {
+        match self {
+            Ratio(r) => Number::from(r.floor()),
+            Float(x) => Float(x.floor()),
+            n => n.clone(),
+        }
+    }
+
+    pub fn ceiling(&self) -> Number {
+        match self {
+            Ratio(r) => Number::from(r.ceil()),
+            Float(x) => Float(x.ceil()),
+            n => n.clone(),
+        }
+    }
+
+    pub fn truncate(&self) -> Number {
+        match self {
+            Ratio(r) => Number::from(r.trunc()),
+            Float(x) => Float(x.trunc()),
+            n => n.clone(),
+        }
+    }
+
+    /// Round to the nearest integer, ties to even.
+    pub fn round(&self) -> Number {
+        match self {
+            Ratio(r) => {
+                let floor = r.floor();
+                let half = BigRational::new(1.into(), 2.into());
+                let up = match (r - &floor).cmp(&half) {
+                    Ordering::Less => false,
+                    Ordering::Greater => true,
+                    Ordering::Equal => floor.to_integer().is_odd(),
+                };
+                Number::from(if up {
+                    floor + BigRational::one()
+                } else {
+                    floor
+                })
+            }
+            Float(x) => Float(x.round_ties_even()),
+            n => n.clone(),
+        }
+    }
+
+    /// Remainder of truncating division, with the sign of `self`; `None` on
+    /// an exact division by zero.
+    pub fn rem(&self, other: &Number) -> Option<Number> {
+        match self.pair(other) {
+            Pair::Int(_, 0) => None,
+            Pair::Int(a, b) => Some(Int(a.checked_rem(b).unwrap_or(0))),
+            Pair::Float(a, b) => Some(Float(a % b)),
+            _ => Some(self.sub(&other.mul(&self.div(other)?.truncate()))),
+        }
+    }
+
+    /// Remainder of flooring division, with the sign of `other`.
+    pub fn modulo(&self, other: &Number) -> Option<Number> {
+        match self.pair(other) {
+            Pair::Int(_, 0) => None,
+            Pair::Int(a, b) => {
+                let r = a.checked_rem(b).unwrap_or(0);
+                Some(Int(if r != 0 && (r < 0) != (b < 0) {
+                    r + b
+                } else {
+                    r
+                }))
+            }
+            Pair::Float(a, b) => {
+                let r = a % b;
+                Some(Float(if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                    r + b
+                } else {
+                    r
+                }))
+            }
+            _ => Some(self.sub(&other.mul(&self.div(other)?.floor()))),
+        }
+    }
+
+    /// `self` to the power `other`, exact for an exact base and integer
+    /// exponent; `None` for zero to a negative exact power.
+    pub fn expt(&self, other: &Number) -> Option<Number> {
+        match other {
+            Int(n) if self.is_exact() => {
+                let magnitude = match u32::try_from(n.unsigned_abs()) {
+                    Ok(magnitude) => magnitude,
+                    Err(_) => return Some(Float(self.to_f64().powf(*n as f64))),
+                };
+                let power = match self {
+                    Ratio(r) => Number::from(Pow::pow(r, magnitude)),
+                    n => Number::from(Pow::pow(n.to_big(), magnitude)),
+                };
+                if *n < 0 {
+                    Int(1).div(&power)
+                } else {
+                    Some(power)
+                }
+            }
+            _ => Some(Float(self.to_f64().powf(other.to_f64()))),
+        }
+    }
+
+    /// Whether the exact result of `self.expt(other)` would have more than
+    /// `MAX_EXPT_BITS` bits. A base of `k` bits to the power `n` has at least
+    /// `(k - 1) * n` of them.
+    pub fn expt_too_large(&self, other: &Number) -> bool {
+        let n = match other {
+            Int(n) if self.is_exact() => n.unsigned_abs(),
+            _ => return false,
+        };
+        if u32::try_from(n).is_err() {
+            return false;
+        }
+        let bits = match self {
+            Ratio(r) => r.numer().bits().max(r.denom().bits()),
+            n => n.to_big().bits(),
+        };
+        bits.saturating_sub(1).saturating_mul(n) > MAX_EXPT_BITS
+    }
+
+    /// Exact for perfect squares of exact numbers, else a float (NaN for
+    /// negative numbers).
+    pub fn sqrt(&self) -> Number {
+        let exact_root = |n: &BigInt| {
+            let root = n.sqrt();
+            if &root * &root == *n {
+                Some(root)
+            } else {
+                None
+            }
+        };
+        let exact = match self {
+            _ if self.is_negative() => None,
+            Int(_) | Big(_) => exact_root(&self.to_big()).map(Number::from),
+            Ratio(r) => exact_root(r.numer())
+                .zip(exact_root(r.denom()))
+                .map(|(numer, denom)| Number::from(BigRational::new(numer, denom))),
+            Float(_) => None,
+        };
+        exact.unwrap_or_else(|| Float(self.to_f64().sqrt()))
+    }
+
+    /// Numeric comparison across types; `None` if either is NaN.
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match self.pair(other) {
+            Pair::Int(a, b) => Some(a.cmp(&b)),
+            Pair::Big(a, b) => Some(a.cmp(&b)),
+            Pair::Ratio(a, b) => Some(a.cmp(&b)),
+            Pair::Float(a, b) => a.partial_cmp(&b),
+        }
+    }
+}
+
+impl Number {
+    /// Exact numbers in any radix from 2 to 36, floats only in radix 10.
+    pub fn to_string_radix(&self, radix: u32) -> Option<String> {
+        match self {
+            _ if radix == 10 => Some(self.to_string()),
+            _ if !(2..=36).contains(&radix) => None,
+            Int(_) | Big(_) => Some(self.to_big().to_str_radix(radix)),
+            Ratio(r) => Some(format!(
+                "{}/{}",
+                r.numer().to_str_radix(radix),
+                r.denom().to_str_radix(radix)
+            )),
+            Float(_) => None,
+        }
+    }
+}
+
+/// For hash table keys. Normalisation makes equal exact numbers identical;
+/// a NaN key is never found again, since NaN is not equal to itself.
+impl Eq for Number {}
+
+impl Hash for Number {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Int(n) => n.hash(state),
+            Big(n) => n.hash(state),
+            Ratio(r) => r.hash(state),
+            // 0.0 and -0.0 are equal
+            Float(x) => (x + 0.0).to_bits().hash(state),
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Self {
+        Int(n)
+    }
+}
+
+impl From<BigInt> for Number {
+    fn from(n: BigInt) -> Self {
+        match n.to_i64() {
+            Some(n) => Int(n),
+            None => Big(n),
+        }
+    }
+}
+
+impl From<BigRational> for Number {
+    fn from(r: BigRational) -> Self {
+        if r.is_integer() {
+            Number::from(r.to_integer())
+        } else {
+            Ratio(r)
+        }
+    }
+}
+
+impl From<f64> for Number {
+    fn from(x: f64) -> Self {
+        Float(x)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Int(n) => write!(f, "{}", n),
+            Big(n) => write!(f, "{}", n),
+            Ratio(r) => write!(f, "{}/{}", r.numer(), r.denom()),
+            Float(x) if x.is_nan() => write!(f, "+nan.0"),
+            Float(x) if x.is_infinite() => write!(f, "{}inf.0", if *x > 0.0 { '+' } else { '-' }),
+            // Debug keeps the `.0` of whole floats and round-trips exactly
+            Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+/// Read a number literal: `-5`, `3.14`, `1e10`, `1/3`, `+inf.0`, or an
+/// integer with a radix prefix `#x`, `#o`, `#b` or `#d`.
+pub fn parse(s: &str) -> Option<Number> {
+    if let Some(rest) = s.strip_prefix('#') {
+        let radix = match rest.chars().next()? {
+            'x' | 'X' => 16,
+            'o' | 'O' => 8,
+            'b' | 'B' => 2,
+            'd' | 'D' => 10,
+            _ => return None,
+        };
+        return integer(&rest[1..], radix);
+    }
+    match s {
+        "+inf.0" => return Some(Float(f64::INFINITY)),
+        "-inf.0" => return Some(Float(f64::NEG_INFINITY)),
+        "+nan.0" | "-nan.0" => return Some(Float(f64::NAN)),
+        _ => (),
+    }
+    if let Some(n) = integer(s, 10) {
+        return Some(n);
+    }
+    if let Some((numer, denom)) = s.split_once('/') {
+        let (numer, denom) = (integer(numer, 10)?, integer(denom, 10)?);
+        if denom.is_zero() || denom.compare(&Int(0)) == Some(Ordering::Less) {
+            return None;
+        }
+        return numer.div(&denom);
+    }
+    let float_chars = s
+        .chars()
+        .all(|c| matches!(c, '0'..='9' | '.' | 'e' | 'E' | '+' | '-'));
+    if float_chars && s.chars().any(|c| c.is_ascii_digit()) {
+        return s.parse().ok().map(Float);
+    }
+    None
+}
+
+/// An optionally signed integer in `radix`, digits only.
+fn integer(s: &str, radix: u32) -> Option<Number> {
+    let digits = s.strip_prefix(|c| c == '-' || c == '+').unwrap_or(s);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    BigInt::parse_bytes(s.as_bytes(), radix).map(Number::from)
+}
+
+#[cfg(test)]
+mod test_number {
+    use crate::number::{parse, Number};
+    use num_bigint::BigInt;
+
+    fn num(s: &str) -> Number {
+        parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_literals() {
+        assert_eq!(num("-5"), Number::Int(-5));
+        assert_eq!(num("+5"), Number::Int(5));
+        assert_eq!(num("2.5"), Number::Float(2.5));
+        assert_eq!(num("1e10"), Number::Float(1e10));
+        assert_eq!(num("-.5"), Number::Float(-0.5));
+        assert_eq!(num("#xff"), Number::Int(255));
+        assert_eq!(num("#b-101"), Number::Int(-5));
+        assert_eq!(num("2/4").to_string(), "1/2");
+        assert_eq!(num("4/2"), Number::Int(2));
+        assert_eq!(
+            num("99999999999999999999"),
+            Number::Big("99999999999999999999".parse::<BigInt>().unwrap())
+        );
+        for s in &[
+            "-", "+", ".", "1/0", "1/-2", "e", "1e", "#x", "#q1", "1_000", "inf",
+        ] {
+            assert_eq!(parse(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rounding_and_division() {
+        assert_eq!(num("7/2").floor().to_string(), "3");
+        assert_eq!(num("-7/2").ceiling().to_string(), "-3");
+        assert_eq!(num("-7/2").truncate().to_string(), "-3");
+        assert_eq!(num("5/2").round().to_string(), "2");
+        assert_eq!(num("7/2").round().to_string(), "4");
+        assert_eq!(num("2.5").round().to_string(), "2.0");
+        assert_eq!(num("-7").rem(&num("2")).unwrap().to_string(), "-1");
+        assert_eq!(num("-7").modulo(&num("2")).unwrap().to_string(), "1");
+        assert_eq!(num("7").modulo(&num("-2")).unwrap().to_string(), "-1");
+        assert_eq!(num("7/2").modulo(&num("1")).unwrap().to_string(), "1/2");
+        assert_eq!(num("-7.5").modulo(&num("2")).unwrap().to_string(), "0.5");
+        assert_eq!(num("1").rem(&num("0")), None);
+        assert_eq!(
+            num("2").expt(&num("100")).unwrap().to_string(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(num("2/3").expt(&num("-2")).unwrap().to_string(), "9/4");
+        assert_eq!(num("0").expt(&num("-1")), None);
+        assert_eq!(num("4").expt(&num("0.5")).unwrap().to_string(), "2.0");
+        assert!(num("10").expt_too_large(&num("4000000000")));
+        assert!(num("1/10").expt_too_large(&num("-4000000000")));
+        assert!(!num("-1").expt_too_large(&num("4000000000")));
+        assert!(!num("2").expt_too_large(&num("1000000")));
+        assert!(!num("10.0").expt_too_large(&num("4000000000")));
+        assert_eq!(num("16/9").sqrt().to_string(), "4/3");
+        assert_eq!(num("2").sqrt().to_string(), "1.4142135623730951");
+    }
+
+    #[test]
+    fn contagion() {
+        let max = Number::Int(i64::MAX);
+        assert_eq!(max.add(&num("1")).to_string(), "9223372036854775808");
+        assert_eq!(max.add(&num("1")).sub(&num("1")), max);
+        assert_eq!(
+            Number::Int(i64::MIN).neg().to_string(),
+            "9223372036854775808"
+        );
+        assert_eq!(num("1").div(&num("3")).unwrap().to_string(), "1/3");
+        assert_eq!(num("1/3").mul(&num("3")), Number::Int(1));
+        assert_eq!(num("1/2").add(&num("0.5")), Number::Float(1.0));
+        assert_eq!(num("1").div(&num("0")), None);
+        assert_eq!(
+            num("1").div(&num("0.0")),
+            Some(Number::Float(f64::INFINITY))
+        );
+        assert_eq!(num("2.0").to_string(), "2.0");
+        assert!(num("1/3").compare(&num("0.3")).unwrap().is_gt());
+    }
+}
@@ -1,22 +1,72 @@
 use super::ast;
+use super::eval::{self, Value};
+use super::number;
+use super::report;
+use super::stack;
+use super::symbol;
 use codespan::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
 
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        ParseError {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// Render the error with a snippet of `source`, named `name`.
+    pub fn report(&self, name: &str, source: &str) -> String {
+        report::render(name, source, &self.message, self.span, Vec::new())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parse error: {}", self.message)
+    }
+}
+
+#[derive(Clone, Copy)]
 enum TokeniseState {
     Start,
     Lparen,
     Rparen,
     Number,
+    Hash,
+    VectorOpen,
+    CharStart,
+    Char,
+    CharName,
     Symbol,
     Whitespace,
     Comment,
     Str,
+    StrEscape,
+    StrEnd,
+    Bar,
+    BarEscape,
+    BarEnd,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
-fn tokenise(source: &str) -> Vec<ast::Token> {
+/// Tokenise `source[offset..]`, keeping spans relative to the whole `source`.
+fn tokenise(source: &str, offset: usize) -> Result<Vec<ast::Token>, ParseError> {
     use TokeniseState::*;
 
     let mut ret = Vec::new();
-    let mut start = 0;
+    let mut start = offset;
 
     loop {
         let mut state = Start;
@@ -47,16 +97,26 @@ fn tokenise(source: &str) -> Vec<ast::Token> {
                     | '@'
                     | '$'
                     | '^' => Some(Symbol),
+                    '#' => Some(Hash),
                     '"' => Some(Str),
+                    '|' => Some(Bar),
+                    '\'' => Some(Quote),
+                    '`' => Some(Quasiquote),
+                    ',' => Some(Unquote),
                     c if c.is_whitespace() => Some(Whitespace),
                     _ => None,
                 },
-                Lparen | Rparen => None,
-                Number => match c {
-                    '0'..='9' => Some(Number),
+                Lparen | Rparen | VectorOpen | Quote | Quasiquote | UnquoteSplicing => None,
+                Unquote => match c {
+                    '@' => Some(UnquoteSplicing),
                     _ => None,
                 },
-                Symbol => match c {
+                Hash if c == '\\' && end == start + 1 => Some(CharStart),
+                Hash if c == '(' && end == start + 1 => Some(VectorOpen),
+                CharStart | CharName if c.is_alphanumeric() => Some(CharName),
+                CharStart => Some(Char),
+                Char | CharName => None,
+                Number | Hash | Symbol => match c {
                     'a'..='z'
                     | 'A'..='Z'
                     | '!'
@@ -75,7 +135,8 @@ fn tokenise(source: &str) -> Vec<ast::Token> {
                     | '@'
                     | '$'
                     | '^'
-                    | '0'..='9' => Some(Symbol),
+                    | '#'
+                    | '0'..='9' => Some(state),
                     _ => None,
                 },
                 Whitespace => {
@@ -92,13 +153,20 @@ fn tokenise(source: &str) -> Vec<ast::Token> {
                         Some(Comment)
                     }
                 }
-                Str => {
-                    if c == '"' {
-                        None
-                    } else {
-                        Some(Str)
-                    }
-                }
+                Str => match c {
+                    '"' => Some(StrEnd),
+                    '\\' => Some(StrEscape),
+                    _ => Some(Str),
+                },
+                StrEscape => Some(Str),
+                StrEnd => None,
+                Bar => match c {
+                    '|' => Some(BarEnd),
+                    '\\' => Some(BarEscape),
+                    _ => Some(Bar),
+                },
+                BarEscape => Some(Bar),
+                BarEnd => None,
             };
 
             if let Some(next_state) = next {
@@ -110,157 +178,1139 @@ fn tokenise(source: &str) -> Vec<ast::Token> {
         }
 
         let token_str = match state {
-            Str => &source[start + 1..end],
+            StrEnd | BarEnd => &source[start + 1..end - 1],
             _ => &source[start..end],
         };
         let span = Span::new(ByteIndex::from(start as u32), ByteIndex::from(end as u32));
-
-        start = end;
+        let span = Span::new(
+            ByteIndex::from(span.start().to_usize() as u32 + 1),
+            ByteIndex::from(span.end().to_usize() as u32 + 1),
+        );
 
         let kind = match state {
-            Start => break,
+            Start if start == source.len() => break,
+            Start => {
+                let c = source[start..].chars().next().unwrap();
+                let end = start as u32 + c.len_utf8() as u32;
+                return Err(ParseError::new(
+                    format!("unexpected character `{}`", c),
+                    Span::new(start as u32 + 1, end + 1),
+                ));
+            }
             Lparen => ast::TokenKind::LeftBracket,
             Rparen => ast::TokenKind::RightBracket,
-            Number => ast::TokenKind::Number(token_str.parse().unwrap()),
-            Symbol => ast::TokenKind::Symbol(token_str.to_string()),
-            Str => ast::TokenKind::Str(token_str.to_string()),
-            Whitespace | Comment => continue,
+            VectorOpen => ast::TokenKind::VectorOpen,
+            Number => ast::TokenKind::Number(
+                number::parse(token_str)
+                    .ok_or_else(|| ParseError::new("invalid number literal", span))?,
+            ),
+            Hash => match token_str {
+                "#t" | "#true" => ast::TokenKind::Bool(true),
+                "#f" | "#false" => ast::TokenKind::Bool(false),
+                _ if token_str.starts_with("#:") && token_str.len() > 2 => {
+                    ast::TokenKind::Symbol(symbol::Symbol::uninterned(&token_str[2..]))
+                }
+                _ => ast::TokenKind::Number(
+                    number::parse(token_str)
+                        .ok_or_else(|| ParseError::new("invalid `#` literal", span))?,
+                ),
+            },
+            CharStart => return Err(ParseError::new("invalid character literal", span)),
+            Char | CharName => {
+                ast::TokenKind::Char(char_literal(&token_str[2..]).ok_or_else(|| {
+                    ParseError::new(format!("unknown character name `{}`", token_str), span)
+                })?)
+            }
+            Symbol => match token_str {
+                "nil" => ast::TokenKind::Nil,
+                "." => ast::TokenKind::Dot,
+                _ => match number::parse(token_str) {
+                    Some(n) => ast::TokenKind::Number(n),
+                    None => ast::TokenKind::Symbol(token_str.into()),
+                },
+            },
+            Str | StrEscape => return Err(ParseError::new("unterminated string literal", span)),
+            StrEnd => ast::TokenKind::Str(unescape(token_str, start + 2)?),
+            Bar | BarEscape => return Err(ParseError::new("unterminated `|` symbol", span)),
+            BarEnd => {
+                let mut name = String::with_capacity(token_str.len());
+                let mut chars = token_str.chars();
+                while let Some(c) = chars.next() {
+                    name.push(match c {
+                        '\\' => chars.next().unwrap_or(c),
+                        c => c,
+                    });
+                }
+                ast::TokenKind::Symbol(name.as_str().into())
+            }
+            Quote => ast::TokenKind::Quote,
+            Quasiquote => ast::TokenKind::Quasiquote,
+            Unquote => ast::TokenKind::Unquote,
+            UnquoteSplicing => ast::TokenKind::UnquoteSplicing,
+            Whitespace | Comment => {
+                start = end;
+                continue;
+            }
         };
 
-        let span = Span::new(
-            ByteIndex::from(span.start().to_usize() as u32 + 1),
-            ByteIndex::from(span.end().to_usize() as u32 + 1),
-        );
+        start = end;
         ret.push(ast::Token::with_span(kind, span));
     }
 
-    ret
+    Ok(ret)
+}
+
+/// The contents of a string literal, which starts at 1-based offset `start`,
+/// with its escape sequences replaced: `\n`, `\t`, `\r`, `\0`, `\\`, `\"`
+/// and `\u{hex}`.
+fn unescape(literal: &str, start: usize) -> Result<String, ParseError> {
+    let mut s = String::with_capacity(literal.len());
+    let mut chars = literal.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        let escape = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '0')) => '\0',
+            Some((_, c @ '\\')) | Some((_, c @ '"')) => c,
+            Some((_, 'u')) => {
+                // skip the `{hex}` that follows, valid or not
+                let braces = &literal[i + 2..];
+                let len = braces.find('}').map_or(braces.len(), |j| j + 1);
+                while chars.peek().is_some_and(|&(j, _)| j < i + 2 + len) {
+                    chars.next();
+                }
+                braces[..len]
+                    .strip_prefix('{')
+                    .and_then(|b| b.strip_suffix('}'))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| {
+                        ParseError::new(
+                            "invalid unicode escape",
+                            Span::new((start + i) as u32, (start + i + 2 + len) as u32),
+                        )
+                    })?
+            }
+            other => {
+                let end = i + 1 + other.map_or(0, |(_, c)| c.len_utf8());
+                return Err(ParseError::new(
+                    format!("unknown escape `{}`", &literal[i..end]),
+                    Span::new((start + i) as u32, (start + end) as u32),
+                ));
+            }
+        };
+        s.push(escape);
+    }
+    Ok(s)
+}
+
+/// Characters written by name after `#\`, other than control characters.
+pub(crate) const CHAR_NAMES: &[(&str, char)] = &[
+    ("space", ' '),
+    ("newline", '\n'),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("nul", '\0'),
+];
+
+/// The character `#\name` stands for: a single character, one of
+/// `CHAR_NAMES`, or `xhex`.
+fn char_literal(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    if let Some(&(_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+        return Some(c);
+    }
+    name.strip_prefix('x')
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .and_then(std::char::from_u32)
 }
 
-struct ParseState<I: Iterator<Item = ast::Token>>(std::iter::Peekable<I>);
+/// Most levels of nesting of code or data: a runaway input, e.g. a long run
+/// of `(`, is an error rather than a parse that never ends.
+pub const MAX_NESTING: usize = 10_000;
+
+struct ParseState<I: Iterator<Item = ast::Token>> {
+    tokens: std::iter::Peekable<I>,
+    /// span of the end of input
+    eof: Span,
+    /// levels of nesting entered, see `nested`
+    depth: usize,
+    /// span of the last token taken
+    last: Span,
+}
 use ast::TokenKind::*;
 
 impl<I> ParseState<I>
 where
     I: Iterator<Item = ast::Token>,
 {
-    fn parse_expr(&mut self) -> ast::Expr {
-        if let Some(token) = self.0.next() {
-            match token.kind {
-                LeftBracket => self.parse_form(token),
-                RightBracket => panic!("unexpected token!"),
-                Number(n) => ast::Expr::Number(token, n),
-                Str(s) => ast::Expr::Str(s.into()),
-                Symbol(ref s) => {
-                    let sym = s.clone();
-                    ast::Expr::Symbol(token, sym)
-                }
-            }
-        } else {
-            panic!("invalid expression")
+    /// Parse one more level down with `parse`, failing at the next token,
+    /// e.g. an opening `(`, past `MAX_NESTING`.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth == MAX_NESTING {
+            let span = self.tokens.peek().map_or(self.eof, ast::Token::span);
+            return Err(ParseError::new("too deeply nested", span));
         }
+        self.depth += 1;
+        let ret = stack::grow(|| parse(self));
+        self.depth -= 1;
+        ret
+    }
+
+    fn parse_expr(&mut self) -> Result<ast::Expr, ParseError> {
+        self.nested(Self::parse_expr_here)
     }
 
-    fn parse_form(&mut self, open: ast::Token) -> ast::Expr {
-        match self.0.peek() {
+    fn parse_expr_here(&mut self) -> Result<ast::Expr, ParseError> {
+        let token = self.next()?;
+        Ok(match token.kind {
+            LeftBracket => self.parse_form(token)?,
+            RightBracket => return Err(ParseError::new("unexpected `)`", token.span())),
+            VectorOpen => {
+                let items = self.parse_vector(Self::parse_datum)?;
+                ast::Expr::Vector(token.span().merge(self.last), eval::Vector::new(items))
+            }
+            Number(ref n) => {
+                let n = n.clone();
+                ast::Expr::Number(token, n)
+            }
+            Str(ref s) => {
+                let s = s.clone();
+                ast::Expr::Str(token, s)
+            }
+            Bool(b) => ast::Expr::Bool(token, b),
+            Char(c) => ast::Expr::Char(token, c),
+            Nil => ast::Expr::Nil(token.clone(), token),
+            Dot => ast::Expr::Symbol(token, ".".into()),
+            Symbol(s) => ast::Expr::Symbol(token, s),
+            Quote => {
+                let datum = self.parse_datum()?;
+                ast::Expr::Quote(token.span().merge(self.last), datum)
+            }
+            Quasiquote => {
+                let template = self.parse_template(1)?;
+                ast::Expr::Quasiquote(token.span().merge(self.last), template)
+            }
+            Unquote => ast::Expr::Unquote(token, Box::new(self.parse_expr()?)),
+            UnquoteSplicing => ast::Expr::UnquoteSplicing(token, Box::new(self.parse_expr()?)),
+        })
+    }
+
+    fn parse_form(&mut self, open: ast::Token) -> Result<ast::Expr, ParseError> {
+        Ok(match self.tokens.peek() {
             Some(&ast::Token {
                 kind: Symbol(ref sym),
                 ..
-            }) => match &sym[..] {
+            }) => match sym.as_str() {
                 "if" => {
-                    let if_tok = self.0.next().unwrap();
-                    let cond = self.parse_expr();
-                    let true_then = self.parse_expr();
-                    let false_then = self.parse_expr();
-                    let close = self.0.next().unwrap();
+                    let if_tok = self.next()?;
+                    let cond = self.parse_expr()?;
+                    let true_then = self.parse_expr()?;
+                    let false_then = self.parse_expr()?;
+                    let close = self.expect_close()?;
                     ast::Expr::If(
                         open,
                         if_tok,
                         Box::new(cond),
-                        Box::new(true_then),
-                        Box::new(false_then),
+                        Rc::new(true_then),
+                        Rc::new(false_then),
                         close,
                     )
                 }
+                "begin" => {
+                    let begin_tok = self.next()?;
+                    let body = self.parse_body()?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Begin(open, begin_tok, body, close)
+                }
                 "setq" => {
-                    let define_tok = self.0.next().unwrap();
-                    let sym_tok = self.0.next().unwrap();
-                    let value = self.parse_expr();
-                    let close = self.0.next().unwrap();
+                    let define_tok = self.next()?;
+                    let sym_tok = self.expect_symbol()?;
+                    let value = self.parse_expr()?;
+                    let close = self.expect_close()?;
                     ast::Expr::Define(open, define_tok, sym_tok, Box::new(value), close)
                 }
-                _ => {
-                    let sym_tok = self.0.next().unwrap();
-                    let mut args = Vec::new();
-                    while let Some(token) = self.0.peek() {
-                        if token.kind == RightBracket {
-                            break;
+                "lambda" => {
+                    let lambda_tok = self.next()?;
+                    let params = self.parse_params()?;
+                    let body = self.parse_body()?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Lambda(open, lambda_tok, params, body, close)
+                }
+                "defun" => {
+                    let defun_tok = self.next()?;
+                    let name_tok = self.expect_symbol()?;
+                    let params = self.parse_params()?;
+                    let body = self.parse_body()?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Defun(open, defun_tok, name_tok, params, body, close)
+                }
+                "defmacro" => {
+                    let defmacro_tok = self.next()?;
+                    let name_tok = self.expect_symbol()?;
+                    let params = self.parse_params()?;
+                    let body = self.parse_body()?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Defmacro(open, defmacro_tok, name_tok, params, body, close)
+                }
+                "let" | "let*" | "letrec" => {
+                    let let_tok = self.next()?;
+                    let bindings = self.parse_bindings()?;
+                    let body = self.parse_body()?;
+                    let close = self.expect_close()?;
+                    match let_tok.kind {
+                        Symbol(ref s) if s == "let" => {
+                            ast::Expr::Let(open, let_tok, bindings, body, close)
+                        }
+                        Symbol(ref s) if s == "let*" => {
+                            ast::Expr::LetStar(open, let_tok, bindings, body, close)
                         }
-                        args.push(self.parse_expr());
+                        _ => ast::Expr::Letrec(open, let_tok, bindings, body, close),
                     }
-                    let close = self.0.next().unwrap();
-                    ast::Expr::Call(open, sym_tok, args, close)
                 }
+                "quote" => {
+                    self.next()?;
+                    let datum = self.parse_datum()?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Quote(open.span().merge(close.span()), datum)
+                }
+                "quasiquote" => {
+                    self.next()?;
+                    let template = self.parse_template(1)?;
+                    let close = self.expect_close()?;
+                    ast::Expr::Quasiquote(open.span().merge(close.span()), template)
+                }
+                "unquote" | "unquote-splicing" => {
+                    let quote_tok = self.next()?;
+                    let expr = Box::new(self.parse_expr()?);
+                    self.expect_close()?;
+                    match quote_tok.kind {
+                        Symbol(ref s) if s == "unquote" => ast::Expr::Unquote(quote_tok, expr),
+                        _ => ast::Expr::UnquoteSplicing(quote_tok, expr),
+                    }
+                }
+                _ => self.parse_call(open)?,
             },
-            other => panic!("invalid expression -> {:?}", other),
+            Some(&ast::Token {
+                kind: RightBracket, ..
+            }) => {
+                let close = self.next()?;
+                ast::Expr::Nil(open, close)
+            }
+            Some(_) => self.parse_call(open)?,
+            None => return Err(ParseError::new("unclosed `(`", open.span())),
+        })
+    }
+
+    /// Read a datum rather than code: `(if)` is just a list here.
+    fn parse_datum(&mut self) -> Result<Value, ParseError> {
+        self.nested(Self::parse_datum_here)
+    }
+
+    fn parse_datum_here(&mut self) -> Result<Value, ParseError> {
+        let token = self.next()?;
+        Ok(match token.kind {
+            LeftBracket => {
+                let (items, tail) = self.parse_list(&token, Self::parse_datum)?;
+                eval::list(items, tail.unwrap_or(Value::Nil))
+            }
+            VectorOpen => Value::Vector(eval::Vector::new(self.parse_vector(Self::parse_datum)?)),
+            Quote | Quasiquote | Unquote | UnquoteSplicing => {
+                let datum = self.parse_datum()?;
+                eval::list(vec![quote_symbol(&token.kind), datum], Value::Nil)
+            }
+            _ => atom(token)?,
+        })
+    }
+
+    /// Read a quasiquote template `depth` quasiquotes deep: data, except
+    /// that what is unquoted at depth 1 is read as code.
+    fn parse_template(&mut self, depth: usize) -> Result<ast::Template, ParseError> {
+        self.nested(|state| state.parse_template_here(depth))
+    }
+
+    fn parse_template_here(&mut self, depth: usize) -> Result<ast::Template, ParseError> {
+        use ast::Template;
+        let token = self.next()?;
+        Ok(match token.kind {
+            LeftBracket => {
+                let head = match self.tokens.peek() {
+                    Some(ast::Token {
+                        kind: Symbol(s), ..
+                    }) => s.as_str(),
+                    _ => "",
+                };
+                let depth = match head {
+                    "unquote" | "unquote-splicing" if depth == 1 => {
+                        self.next()?;
+                        let expr = Box::new(self.parse_expr()?);
+                        self.expect_close()?;
+                        return Ok(match head {
+                            "unquote" => Template::Unquote(expr),
+                            _ => Template::UnquoteSplicing(expr),
+                        });
+                    }
+                    "quasiquote" => depth + 1,
+                    "unquote" | "unquote-splicing" => depth - 1,
+                    _ => depth,
+                };
+                let (items, tail) = self.parse_list(&token, |state| state.parse_template(depth))?;
+                Template::List(items, Box::new(tail.unwrap_or(Template::Datum(Value::Nil))))
+            }
+            VectorOpen => Template::Vector(self.parse_vector(|state| state.parse_template(depth))?),
+            Unquote if depth == 1 => Template::Unquote(Box::new(self.parse_expr()?)),
+            UnquoteSplicing if depth == 1 => {
+                Template::UnquoteSplicing(Box::new(self.parse_expr()?))
+            }
+            Quote | Quasiquote | Unquote | UnquoteSplicing => {
+                let datum = self.parse_template(match token.kind {
+                    Quote => depth,
+                    Quasiquote => depth + 1,
+                    _ => depth - 1,
+                })?;
+                Template::List(
+                    vec![Template::Datum(quote_symbol(&token.kind)), datum],
+                    Box::new(Template::Datum(Value::Nil)),
+                )
+            }
+            _ => Template::Datum(atom(token)?),
+        })
+    }
+
+    /// The items of a list whose `open` has been taken, each read by `item`,
+    /// and its tail if it is dotted.
+    fn parse_list<T>(
+        &mut self,
+        open: &ast::Token,
+        item: impl Fn(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<(Vec<T>, Option<T>), ParseError> {
+        let mut items = Vec::new();
+        let tail = loop {
+            match self.tokens.peek() {
+                Some(&ast::Token {
+                    kind: RightBracket, ..
+                }) => break None,
+                Some(&ast::Token { kind: Dot, .. }) => {
+                    let dot = self.next()?;
+                    if items.is_empty() {
+                        return Err(ParseError::new("unexpected `.`", dot.span()));
+                    }
+                    break Some(item(self)?);
+                }
+                Some(_) => items.push(item(self)?),
+                None => return Err(ParseError::new("unclosed `(`", open.span())),
+            }
+        };
+        self.expect_close()?;
+        Ok((items, tail))
+    }
+
+    /// The items of a vector whose `#(` has been taken, each read by `item`.
+    fn parse_vector<T>(
+        &mut self,
+        item: impl Fn(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut items = Vec::new();
+        while let Some(token) = self.tokens.peek() {
+            if token.kind == RightBracket {
+                break;
+            }
+            items.push(item(self)?);
+        }
+        self.expect_close()?;
+        Ok(items)
+    }
+
+    fn parse_call(&mut self, open: ast::Token) -> Result<ast::Expr, ParseError> {
+        let head = self.parse_expr()?;
+        let args = self.parse_args()?;
+        let close = self.expect_close()?;
+        Ok(ast::Expr::Call(
+            open,
+            Box::new(head),
+            args,
+            close,
+            ast::Expansion::default(),
+        ))
+    }
+
+    fn parse_params(&mut self) -> Result<Vec<ast::Token>, ParseError> {
+        self.expect_open("parameter list")?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.kind {
+                RightBracket => break,
+                Symbol(_) => params.push(token),
+                _ => return Err(ParseError::new("parameter must be a symbol", token.span())),
+            }
+        }
+        Ok(params)
+    }
+
+    fn parse_bindings(&mut self) -> Result<Vec<(ast::Token, ast::Expr)>, ParseError> {
+        self.expect_open("binding list")?;
+        let mut bindings = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.kind {
+                RightBracket => break,
+                LeftBracket => {
+                    let sym_tok = self.expect_symbol()?;
+                    let value = self.parse_expr()?;
+                    self.expect_close()?;
+                    bindings.push((sym_tok, value));
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        "binding must be a `(name value)` pair",
+                        token.span(),
+                    ))
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    fn parse_args(&mut self) -> Result<Vec<ast::Expr>, ParseError> {
+        let mut args = Vec::new();
+        while let Some(token) = self.tokens.peek() {
+            if token.kind == RightBracket {
+                break;
+            }
+            args.push(self.parse_expr()?);
         }
+        Ok(args)
     }
+
+    fn parse_body(&mut self) -> Result<ast::Body, ParseError> {
+        Ok(self.parse_args()?.into_iter().map(Rc::new).collect())
+    }
+
+    fn next(&mut self) -> Result<ast::Token, ParseError> {
+        let token = self
+            .tokens
+            .next()
+            .ok_or_else(|| ParseError::new("unexpected end of input", self.eof))?;
+        self.last = token.span();
+        Ok(token)
+    }
+
+    fn expect_open(&mut self, what: &str) -> Result<ast::Token, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            LeftBracket => Ok(token),
+            _ => Err(ParseError::new(format!("expected {}", what), token.span())),
+        }
+    }
+
+    fn expect_close(&mut self) -> Result<ast::Token, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            RightBracket => Ok(token),
+            _ => Err(ParseError::new("expected `)`", token.span())),
+        }
+    }
+
+    fn expect_symbol(&mut self) -> Result<ast::Token, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            Symbol(_) => Ok(token),
+            _ => Err(ParseError::new("expected a symbol", token.span())),
+        }
+    }
+}
+
+/// The value of a token read as an atom, i.e. anything but a bracket or a
+/// quote.
+fn atom(token: ast::Token) -> Result<Value, ParseError> {
+    Ok(match token.kind {
+        Number(n) => Value::Number(n),
+        Str(s) => Value::Str(s),
+        Bool(b) => Value::Bool(b),
+        Char(c) => Value::Char(c),
+        Nil => Value::Nil,
+        Symbol(s) => Value::Symbol(s),
+        RightBracket => return Err(ParseError::new("unexpected `)`", token.span())),
+        Dot => return Err(ParseError::new("unexpected `.`", token.span())),
+        other => unreachable!("{:?} is not an atom", other),
+    })
 }
 
-pub fn parse(source: &str) -> ast::Expr {
-    let tokens = tokenise(source);
-    if tokens.len() < 1 {
-        println!("bye");
-        std::process::exit(0)
+/// The symbol a quote token abbreviates, e.g. `quote` for `'`.
+fn quote_symbol(kind: &ast::TokenKind) -> Value {
+    Value::Symbol(
+        match kind {
+            Quote => "quote",
+            Quasiquote => "quasiquote",
+            Unquote => "unquote",
+            _ => "unquote-splicing",
+        }
+        .into(),
+    )
+}
+
+fn parse_state(
+    source: &str,
+    offset: usize,
+) -> Result<ParseState<std::vec::IntoIter<ast::Token>>, ParseError> {
+    let eof = source.len() as u32 + 1;
+    Ok(ParseState {
+        tokens: tokenise(source, offset)?.into_iter().peekable(),
+        eof: Span::new(eof, eof),
+        depth: 0,
+        last: Span::new(eof, eof),
+    })
+}
+
+pub fn parse(source: &str) -> Result<ast::Expr, ParseError> {
+    parse_at(source, 0)
+}
+
+/// Parse the expression starting at byte `offset` of `source`.
+/// Spans stay relative to the whole `source`, so a REPL can keep one history buffer.
+pub fn parse_at(source: &str, offset: usize) -> Result<ast::Expr, ParseError> {
+    parse_state(source, offset)?.parse_expr()
+}
+
+/// Whether `name` reads back as the symbol it names, e.g. `a`, where `a b`,
+/// `1` or `nil` don't.
+pub(crate) fn is_symbol_name(name: &str) -> bool {
+    matches!(
+        tokenise(name, 0).as_deref(),
+        Ok([ast::Token { kind: Symbol(s), .. }]) if s == name
+    )
+}
+
+/// Read the first datum in `source` as data, the inverse of `print::write`.
+pub fn read(source: &str) -> Result<Value, ParseError> {
+    parse_state(source, 0)?.parse_datum()
+}
+
+/// Read back code from data, e.g. the expansion of a macro, as the parser
+/// reads its printed form. Every token gets `span`, the span of the form the
+/// data came from.
+pub fn from_value(value: &Value, span: Span) -> Result<ast::Expr, ParseError> {
+    Coder {
+        span,
+        depth: 0,
+        open: HashSet::new(),
     }
-    ParseState(tokens.into_iter().peekable()).parse_expr()
+    .expr(value)
+}
+
+/// Turns data into code for `from_value`.
+struct Coder {
+    span: Span,
+    /// levels of nesting entered, as in `ParseState`
+    depth: usize,
+    /// the conses and vectors being turned into code, to catch circular data
+    open: HashSet<*const ()>,
+}
+
+impl Coder {
+    fn token(&self, kind: ast::TokenKind) -> ast::Token {
+        ast::Token::with_span(kind, self.span)
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError::new(message, self.span)
+    }
+
+    /// Run `f` one level of nesting down into the conses or vector at
+    /// `addrs`, failing if one is already open, i.e. the data contains itself.
+    fn nested<T>(
+        &mut self,
+        addrs: Vec<*const ()>,
+        f: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error("too deeply nested"));
+        }
+        for &addr in &addrs {
+            if !self.open.insert(addr) {
+                return Err(self.error("can't use circular data as code"));
+            }
+        }
+        self.depth += 1;
+        let ret = stack::grow(|| f(self));
+        self.depth -= 1;
+        for addr in addrs {
+            self.open.remove(&addr);
+        }
+        ret
+    }
+
+    /// Run `f` on the items of the list `value` and its tail, `()` unless it
+    /// is dotted.
+    fn list<T>(
+        &mut self,
+        value: &Value,
+        what: &str,
+        f: impl FnOnce(&mut Self, Vec<Value>, Value) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        let mut addrs = HashSet::new();
+        let mut items = Vec::new();
+        let mut tail = match value {
+            Value::Nil | Value::Cons(_) => value.clone(),
+            _ => return Err(self.error(format!("expected {}", what))),
+        };
+        while let Value::Cons(cons) = tail {
+            if !addrs.insert(cons.addr()) {
+                return Err(self.error("can't use circular data as code"));
+            }
+            items.push(cons.car());
+            tail = cons.cdr();
+        }
+        self.nested(addrs.into_iter().collect(), |coder| f(coder, items, tail))
+    }
+
+    /// The items of `value` as the parser reads them as code, where a dotted
+    /// list ends in `.` and its tail.
+    fn items<T>(
+        &mut self,
+        value: &Value,
+        what: &str,
+        f: impl FnOnce(&mut Self, Vec<Value>) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        self.list(value, what, |coder, mut items, tail| {
+            if tail != Value::Nil {
+                items.push(Value::Symbol(".".into()));
+                items.push(tail);
+            }
+            f(coder, items)
+        })
+    }
+
+    fn expr(&mut self, value: &Value) -> Result<ast::Expr, ParseError> {
+        use ast::Expr;
+        Ok(match value {
+            Value::Number(n) => Expr::Number(self.token(Number(n.clone())), n.clone()),
+            Value::Str(s) => Expr::Str(self.token(Str(s.clone())), s.clone()),
+            Value::Bool(b) => Expr::Bool(self.token(Bool(*b)), *b),
+            Value::Char(c) => Expr::Char(self.token(Char(*c)), *c),
+            Value::Symbol(s) => Expr::Symbol(self.token(Symbol(*s)), *s),
+            Value::Nil => Expr::Nil(self.token(LeftBracket), self.token(RightBracket)),
+            Value::Cons(_) => self.items(value, "a list", Self::form)?,
+            Value::Vector(vector) => Expr::Vector(self.span, vector.clone()),
+            other => return Err(self.error(format!("can't use {} as code", other.type_name()))),
+        })
+    }
+
+    /// The code of a list of `items`, as `ParseState::parse_form` reads it.
+    fn form(&mut self, items: Vec<Value>) -> Result<ast::Expr, ParseError> {
+        use ast::Expr;
+        let (open, close) = (self.token(LeftBracket), self.token(RightBracket));
+        let (head, args) = items.split_first().expect("a list has a head");
+        let keyword = match head {
+            Value::Symbol(s) => s.as_str(),
+            _ => "",
+        };
+        let keyword_tok = self.token(Symbol(keyword.into()));
+        Ok(match (keyword, args) {
+            ("if", [cond, true_then, false_then]) => Expr::If(
+                open,
+                keyword_tok,
+                Box::new(self.expr(cond)?),
+                Rc::new(self.expr(true_then)?),
+                Rc::new(self.expr(false_then)?),
+                close,
+            ),
+            ("begin", body) => Expr::Begin(open, keyword_tok, self.body(body)?, close),
+            ("setq", [sym, value]) => Expr::Define(
+                open,
+                keyword_tok,
+                self.symbol(sym)?,
+                Box::new(self.expr(value)?),
+                close,
+            ),
+            ("lambda", [params, body @ ..]) => Expr::Lambda(
+                open,
+                keyword_tok,
+                self.params(params)?,
+                self.body(body)?,
+                close,
+            ),
+            ("defun", [name, params, body @ ..]) => Expr::Defun(
+                open,
+                keyword_tok,
+                self.symbol(name)?,
+                self.params(params)?,
+                self.body(body)?,
+                close,
+            ),
+            ("defmacro", [name, params, body @ ..]) => Expr::Defmacro(
+                open,
+                keyword_tok,
+                self.symbol(name)?,
+                self.params(params)?,
+                self.body(body)?,
+                close,
+            ),
+            ("let", [bindings, body @ ..]) => Expr::Let(
+                open,
+                keyword_tok,
+                self.bindings(bindings)?,
+                self.body(body)?,
+                close,
+            ),
+            ("let*", [bindings, body @ ..]) => Expr::LetStar(
+                open,
+                keyword_tok,
+                self.bindings(bindings)?,
+                self.body(body)?,
+                close,
+            ),
+            ("letrec", [bindings, body @ ..]) => Expr::Letrec(
+                open,
+                keyword_tok,
+                self.bindings(bindings)?,
+                self.body(body)?,
+                close,
+            ),
+            ("quote", [datum]) => Expr::Quote(self.span, datum.clone()),
+            ("quasiquote", [template]) => Expr::Quasiquote(self.span, self.template(template, 1)?),
+            ("unquote", [expr]) => Expr::Unquote(keyword_tok, Box::new(self.expr(expr)?)),
+            ("unquote-splicing", [expr]) => {
+                Expr::UnquoteSplicing(keyword_tok, Box::new(self.expr(expr)?))
+            }
+            ("if", _)
+            | ("setq", _)
+            | ("lambda", _)
+            | ("defun", _)
+            | ("defmacro", _)
+            | ("let", _)
+            | ("let*", _)
+            | ("letrec", _)
+            | ("quote", _)
+            | ("quasiquote", _)
+            | ("unquote", _)
+            | ("unquote-splicing", _) => return Err(self.error(format!("malformed `{}`", keyword))),
+            _ => Expr::Call(
+                open,
+                Box::new(self.expr(head)?),
+                args.iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<Result<_, _>>()?,
+                close,
+                ast::Expansion::default(),
+            ),
+        })
+    }
+
+    fn body(&mut self, values: &[Value]) -> Result<ast::Body, ParseError> {
+        values
+            .iter()
+            .map(|value| self.expr(value).map(Rc::new))
+            .collect()
+    }
+
+    fn symbol(&self, value: &Value) -> Result<ast::Token, ParseError> {
+        match value {
+            Value::Symbol(s) => Ok(self.token(Symbol(*s))),
+            _ => Err(self.error("expected a symbol")),
+        }
+    }
+
+    fn params(&mut self, value: &Value) -> Result<Vec<ast::Token>, ParseError> {
+        self.list(value, "parameter list", |coder, items, tail| {
+            if tail != Value::Nil {
+                return Err(coder.error("parameter must be a symbol"));
+            }
+            items
+                .iter()
+                .map(|item| {
+                    coder
+                        .symbol(item)
+                        .map_err(|_| coder.error("parameter must be a symbol"))
+                })
+                .collect()
+        })
+    }
+
+    fn bindings(&mut self, value: &Value) -> Result<Vec<(ast::Token, ast::Expr)>, ParseError> {
+        self.items(value, "binding list", |coder, bindings| {
+            bindings
+                .iter()
+                .map(|binding| {
+                    coder.items(binding, "binding", |coder, items| match &items[..] {
+                        [sym, value] => Ok((coder.symbol(sym)?, coder.expr(value)?)),
+                        _ => Err(coder.error("binding must be a `(name value)` pair")),
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// A quasiquote template `depth` quasiquotes deep, as
+    /// `ParseState::parse_template` reads it.
+    fn template(&mut self, value: &Value, depth: usize) -> Result<ast::Template, ParseError> {
+        use ast::Template;
+        Ok(match value {
+            Value::Cons(_) => self.list(value, "a list", |coder, items, tail| {
+                let head = match &items[0] {
+                    Value::Symbol(s) => s.as_str(),
+                    _ => "",
+                };
+                let depth = match head {
+                    "unquote" | "unquote-splicing" if depth == 1 => {
+                        let expr = match (&items[1..], &tail) {
+                            ([expr], Value::Nil) => Box::new(coder.expr(expr)?),
+                            _ => return Err(coder.error(format!("malformed `{}`", head))),
+                        };
+                        return Ok(match head {
+                            "unquote" => Template::Unquote(expr),
+                            _ => Template::UnquoteSplicing(expr),
+                        });
+                    }
+                    "quasiquote" => depth + 1,
+                    "unquote" | "unquote-splicing" => depth - 1,
+                    _ => depth,
+                };
+                let items = items
+                    .iter()
+                    .map(|item| coder.template(item, depth))
+                    .collect::<Result<_, _>>()?;
+                Ok(Template::List(
+                    items,
+                    Box::new(coder.template(&tail, depth)?),
+                ))
+            })?,
+            Value::Vector(vector) => {
+                Template::Vector(self.nested(vec![vector.addr()], |coder| {
+                    vector
+                        .to_vec()
+                        .iter()
+                        .map(|item| coder.template(item, depth))
+                        .collect()
+                })?)
+            }
+            other => Template::Datum(other.clone()),
+        })
+    }
+}
+
+/// Parse every top-level form in `source`, in order.
+pub fn parse_program(source: &str) -> Result<Vec<ast::Expr>, ParseError> {
+    parse_program_at(source, 0)
+}
+
+/// Like `parse_program`, for the forms starting at byte `offset`, as `parse_at`.
+pub fn parse_program_at(source: &str, offset: usize) -> Result<Vec<ast::Expr>, ParseError> {
+    let mut state = parse_state(source, offset)?;
+    let mut program = Vec::new();
+    while state.tokens.peek().is_some() {
+        program.push(state.parse_expr()?);
+    }
+    Ok(program)
 }
 
 #[cfg(test)]
 mod test_parse {
+    use crate::number::Number;
     use crate::{ast, parse};
     use ast::{Expr, Token, TokenKind};
     use big_s::S;
     use codespan::*;
+    use std::rc::Rc;
 
     #[test]
     fn tokenise_symbol() {
         let str = "(+ n 1)";
         let tokens = vec![
             ast::TokenKind::LeftBracket,
-            ast::TokenKind::Symbol(S("+")),
-            ast::TokenKind::Symbol(S("n")),
-            ast::TokenKind::Number(1),
+            ast::TokenKind::Symbol("+".into()),
+            ast::TokenKind::Symbol("n".into()),
+            ast::TokenKind::Number(Number::Int(1)),
             ast::TokenKind::RightBracket,
         ];
 
-        assert!(parse::tokenise(str)
+        assert!(parse::tokenise(str, 0)
+            .unwrap()
             .iter()
             .map(|t| t.kind.clone())
             .collect::<Vec<_>>()
             .eq(&tokens));
     }
 
+    #[test]
+    fn tokenise_numbers() {
+        let kinds = |src| {
+            parse::tokenise(src, 0)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds("-5 - -x 2.5 #x1F 1/3"),
+            vec![
+                TokenKind::Number(Number::Int(-5)),
+                TokenKind::Symbol("-".into()),
+                TokenKind::Symbol("-x".into()),
+                TokenKind::Number(Number::Float(2.5)),
+                TokenKind::Number(Number::Int(31)),
+                TokenKind::Number(crate::number::parse("1/3").unwrap()),
+            ]
+        );
+        let err = parse::tokenise("(+ 1x 2)", 0).unwrap_err();
+        assert_eq!(err.message, "invalid number literal");
+        assert_eq!(err.span, Span::new(4, 6));
+    }
+
     #[test]
     fn parse_expr() {
         let src = "(if 1 1 2)";
         assert_eq!(
-            parse::parse(src),
+            parse::parse(src).unwrap(),
             Expr::If(
                 Token::with_span(TokenKind::LeftBracket, Span::new(1, 2)),
-                Token::with_span(TokenKind::Symbol(S("if")), Span::new(2, 4)),
+                Token::with_span(TokenKind::Symbol("if".into()), Span::new(2, 4)),
                 Box::new(create_number(1, (5, 6))),
-                Box::new(create_number(1, (7, 8))),
-                Box::new(create_number(2, (9, 10))),
+                Rc::new(create_number(1, (7, 8))),
+                Rc::new(create_number(2, (9, 10))),
                 Token::with_span(TokenKind::RightBracket, Span::new(10, 11))
             )
         );
     }
 
+    #[test]
+    fn parse_program() {
+        let program = parse::parse_program("(setq x 1) ; comment\n(print \"x\") x").unwrap();
+        assert_eq!(program.len(), 3);
+        assert!(matches!(program[2], Expr::Symbol(_, ref s) if s == "x"));
+        assert!(parse::parse_program("  ").unwrap().is_empty());
+
+        let history = "(setq x 1)\n(setq y 2) (+ x y)\n";
+        let program = parse::parse_program_at(history, 11).unwrap();
+        assert_eq!(program.len(), 2);
+        assert_eq!(program[1].span(), Span::new(23, 30));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse::parse("(+ 1 2").unwrap_err();
+        assert_eq!(err.message, "unexpected end of input");
+        assert_eq!(err.span, Span::new(7, 7));
+
+        let err = parse::parse_program("(print 1))").unwrap_err();
+        assert_eq!(err.message, "unexpected `)`");
+        assert_eq!(err.span, Span::new(10, 11));
+
+        let err = parse::parse("(print \"oops)").unwrap_err();
+        assert_eq!(err.message, "unterminated string literal");
+
+        let deep = format!("'{}", "(".repeat(200_000));
+        let err = parse::parse(&deep).unwrap_err();
+        assert_eq!(err.message, "too deeply nested");
+        let at = parse::MAX_NESTING as u32;
+        assert_eq!(err.span, Span::new(at + 1, at + 2));
+        assert_eq!(parse::read(&deep[1..]).unwrap_err().span, err.span);
+        let nested = format!("{}{}", "(list ".repeat(5000), ")".repeat(5000));
+        assert!(parse::parse(&nested).is_ok());
+    }
+
+    #[test]
+    fn read_data() {
+        use crate::eval::{list, Value};
+        assert_eq!(
+            parse::read(r#"(if "a \"b\"" . nil)"#).unwrap(),
+            list(
+                vec![Value::Symbol("if".into()), Value::Str(S("a \"b\""))],
+                Value::Nil
+            )
+        );
+        assert!(matches!(parse::parse("nil").unwrap(), Expr::Nil(..)));
+        assert_eq!(parse::read("(1 . )").unwrap_err().message, "unexpected `)`");
+        assert_eq!(parse::read("(. 2)").unwrap_err().message, "unexpected `.`");
+        assert_eq!(
+            parse::read("(1 . 2 3)").unwrap_err().message,
+            "expected `)`"
+        );
+    }
+
+    #[test]
+    fn string_escapes() {
+        let string = |src| match parse::parse(src) {
+            Ok(Expr::Str(_, s)) => s,
+            other => panic!("not a string: {:?}", other),
+        };
+        assert_eq!(string(r#""a \"b\"""#), "a \"b\"");
+        assert_eq!(string(r#""\n\t\r\0\\""#), "\n\t\r\0\\");
+        assert_eq!(string(r#""\u{3bb} \u{1F600}""#), "\u{3bb} \u{1F600}");
+
+        let err = parse::parse(r#"(print "ab\q")"#).unwrap_err();
+        assert_eq!(err.message, "unknown escape `\\q`");
+        assert_eq!(err.span, Span::new(11, 13));
+        let err = parse::parse(r#""\u{110000}""#).unwrap_err();
+        assert_eq!(err.message, "invalid unicode escape");
+        assert_eq!(err.span, Span::new(2, 12));
+        assert!(parse::parse(r#""\u{41""#).is_err());
+    }
+
+    #[test]
+    fn vector_literals() {
+        use crate::eval::{list, Value, Vector};
+        assert_eq!(
+            parse::read("#(1 (a) #())").unwrap(),
+            Value::Vector(Vector::new(vec![
+                Value::int(1),
+                list(vec![Value::Symbol("a".into())], Value::Nil),
+                Value::Vector(Vector::new(Vec::new())),
+            ]))
+        );
+        assert_eq!(
+            parse::read("#(1").unwrap_err().message,
+            "unexpected end of input"
+        );
+    }
+
+    #[test]
+    fn char_literals() {
+        use crate::eval::{list, Value};
+        use parse::read;
+        assert_eq!(read(r"#\a"), Ok(Value::Char('a')));
+        assert_eq!(read(r"#\space"), Ok(Value::Char(' ')));
+        assert_eq!(read(r"#\x3bb"), Ok(Value::Char('λ')));
+        assert_eq!(read(r"#\x"), Ok(Value::Char('x')));
+        assert_eq!(read(r"#\)"), Ok(Value::Char(')')));
+        assert_eq!(read(r"(#\()"), Ok(list(vec![Value::Char('(')], Value::Nil)));
+        assert_eq!(
+            read(r"#\bogus").unwrap_err().message,
+            r"unknown character name `#\bogus`"
+        );
+        assert_eq!(
+            read(r"#\").unwrap_err().message,
+            "invalid character literal"
+        );
+    }
+
+    #[test]
+    fn report_parse_error() {
+        let src = "(setq x 1)\n(lambda x)";
+        let report = parse::parse_program(src)
+            .unwrap_err()
+            .report("test.al", src);
+        assert!(report.contains("error: expected parameter list"));
+        assert!(report.contains("test.al:2:9"));
+        assert!(report.contains("^"));
+    }
+
     fn create_number(n: i64, span: (u32, u32)) -> Expr {
         Expr::Number(
-            Token::with_span(TokenKind::Number(n), Span::new(span.0, span.1)),
-            n,
+            Token::with_span(TokenKind::Number(Number::Int(n)), Span::new(span.0, span.1)),
+            Number::Int(n),
         )
     }
 }
@@ -0,0 +1,422 @@
+use super::eval::{Cons, Port, Value};
+use super::parse::{self, CHAR_NAMES};
+use super::stack;
+use super::symbol::Symbol;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// How a value is rendered: `Write` output reads back as the same datum,
+/// `Display` shows strings without quotes or escapes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Style {
+    Write,
+    Display,
+}
+
+/// A value formatted in a `Style`, for use with `format!` and friends.
+pub struct Printed<'a> {
+    value: &'a Value,
+    style: Style,
+}
+
+pub fn write(value: &Value) -> Printed<'_> {
+    Printed {
+        value,
+        style: Style::Write,
+    }
+}
+
+pub fn display(value: &Value) -> Printed<'_> {
+    Printed {
+        value,
+        style: Style::Display,
+    }
+}
+
+/// The conses and vectors that are part of a cycle, by address, with the
+/// number each is labelled with once printed, as `#0=(a . #0#)`.
+struct Labels {
+    ids: HashMap<*const (), Option<usize>>,
+    next: usize,
+}
+
+impl Labels {
+    /// Find the cycles in `value` with a depth-first walk: a cell reached again
+    /// while its own walk is in progress closes a cycle.
+    fn find(value: &Value) -> Self {
+        enum Visit {
+            Enter(Value),
+            Leave(*const ()),
+        }
+        let mut ids = HashMap::new();
+        let mut in_progress = HashSet::new();
+        let mut done = HashSet::new();
+        let mut stack = vec![Visit::Enter(value.clone())];
+        while let Some(visit) = stack.pop() {
+            let value = match visit {
+                Visit::Enter(value) => value,
+                Visit::Leave(addr) => {
+                    in_progress.remove(&addr);
+                    done.insert(addr);
+                    continue;
+                }
+            };
+            let (addr, children) = match &value {
+                Value::Cons(cons) => (cons.addr(), vec![cons.cdr(), cons.car()]),
+                Value::Vector(vector) => (vector.addr(), vector.to_vec()),
+                _ => continue,
+            };
+            if in_progress.contains(&addr) {
+                ids.insert(addr, None);
+                continue;
+            }
+            if done.contains(&addr) {
+                continue;
+            }
+            in_progress.insert(addr);
+            stack.push(Visit::Leave(addr));
+            stack.extend(children.into_iter().map(Visit::Enter));
+        }
+        Labels { ids, next: 0 }
+    }
+
+    /// Write the label of the cell at `addr` if it has one: `#n=` before the
+    /// cell the first time, `#n#` in place of it after that, when `true` is
+    /// returned.
+    fn write(&mut self, f: &mut fmt::Formatter, addr: *const ()) -> Result<bool, fmt::Error> {
+        match self.ids.get_mut(&addr) {
+            None => Ok(false),
+            Some(Some(id)) => write!(f, "#{}#", id).map(|_| true),
+            Some(id) => {
+                *id = Some(self.next);
+                self.next += 1;
+                write!(f, "#{}=", self.next - 1).map(|_| false)
+            }
+        }
+    }
+}
+
+struct Printer {
+    style: Style,
+    labels: Labels,
+}
+
+impl Printer {
+    fn value(&mut self, f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+        stack::grow(|| self.value_here(f, value))
+    }
+
+    fn value_here(&mut self, f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+        match value {
+            Value::Cons(cons) => {
+                if self.labels.write(f, cons.addr())? {
+                    return Ok(());
+                }
+                self.list(f, cons)
+            }
+            Value::Vector(vector) => {
+                if self.labels.write(f, vector.addr())? {
+                    return Ok(());
+                }
+                write!(f, "#(")?;
+                for (i, item) in vector.to_vec().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    self.value(f, item)?;
+                }
+                write!(f, ")")
+            }
+            atom => write_atom(f, atom, self.style),
+        }
+    }
+
+    fn list(&mut self, f: &mut fmt::Formatter, cons: &Cons) -> fmt::Result {
+        if let Some((prefix, datum)) = quote_prefix(cons) {
+            if !self.is_labelled(&cons.cdr()) {
+                write!(f, "{}", prefix)?;
+                return self.value(f, &datum);
+            }
+        }
+        write!(f, "(")?;
+        self.value(f, &cons.car())?;
+        let mut rest = cons.cdr();
+        while let Value::Cons(cons) = &rest {
+            if self.is_labelled(&rest) {
+                break;
+            }
+            write!(f, " ")?;
+            self.value(f, &cons.car())?;
+            rest = cons.cdr();
+        }
+        if rest != Value::Nil {
+            write!(f, " . ")?;
+            self.value(f, &rest)?;
+        }
+        write!(f, ")")
+    }
+
+    fn is_labelled(&self, value: &Value) -> bool {
+        match value {
+            Value::Cons(cons) => self.labels.ids.contains_key(&cons.addr()),
+            _ => false,
+        }
+    }
+}
+
+/// `(quote x)` and friends print as the reader shorthand `'x`.
+fn quote_prefix(cons: &Cons) -> Option<(&'static str, Value)> {
+    let prefix = match cons.car() {
+        Value::Symbol(s) => match s.as_str() {
+            "quote" => "'",
+            "quasiquote" => "`",
+            "unquote" => ",",
+            "unquote-splicing" => ",@",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match cons.cdr() {
+        Value::Cons(rest) if rest.cdr() == Value::Nil => Some((prefix, rest.car())),
+        _ => None,
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => write!(f, "\\{}", c)?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\0' => write!(f, "\\0")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Write `s` so it reads back: a gensym as `#:name`, which reads as a new
+/// uninterned symbol, and a name that would read as something else between
+/// `|`s, e.g. `|a b|`, `|1|` or `|nil|`.
+fn write_symbol(f: &mut fmt::Formatter, s: Symbol) -> fmt::Result {
+    if !s.is_interned() {
+        return write!(f, "#:{}", s);
+    }
+    if parse::is_symbol_name(s.as_str()) {
+        return write!(f, "{}", s);
+    }
+    write!(f, "|")?;
+    for c in s.as_str().chars() {
+        if c == '|' || c == '\\' {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    write!(f, "|")
+}
+
+fn write_char(f: &mut fmt::Formatter, c: char) -> fmt::Result {
+    match CHAR_NAMES.iter().find(|&&(_, named)| named == c) {
+        Some((name, _)) => write!(f, "#\\{}", name),
+        None if c.is_control() || c.is_whitespace() => write!(f, "#\\x{:x}", c as u32),
+        None => write!(f, "#\\{}", c),
+    }
+}
+
+impl fmt::Display for Printed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = match self.value {
+            Value::Cons(_) | Value::Vector(_) => Labels::find(self.value),
+            _ => Labels {
+                ids: HashMap::new(),
+                next: 0,
+            },
+        };
+        Printer {
+            style: self.style,
+            labels,
+        }
+        .value(f, self.value)
+    }
+}
+
+/// Write anything but a cons or a vector.
+fn write_atom(f: &mut fmt::Formatter, value: &Value, style: Style) -> fmt::Result {
+    match value {
+        Value::Number(n) => write!(f, "{}", n),
+        Value::Callable(c) => write!(f, "<callable {:x?}>", c),
+        Value::Native(native) => write!(f, "<native {}>", native.name),
+        Value::Closure(l) => match &l.name {
+            Some(name) => write!(f, "<closure {}>", name),
+            None => write!(f, "<lambda>"),
+        },
+        Value::Macro(l) => write!(f, "<macro {}>", l.name.as_deref().unwrap_or("")),
+        Value::Cons(_) | Value::Vector(_) => unreachable!("not an atom"),
+        Value::HashTable(table) => write!(f, "<hash-table {}>", table.len()),
+        Value::Port(Port::Input(_)) => write!(f, "<input-port>"),
+        Value::Port(Port::Output(_)) => write!(f, "<output-port>"),
+        Value::Str(s) if style == Style::Write => write_str(f, s),
+        Value::Str(s) => write!(f, "{}", s),
+        Value::Symbol(s) if style == Style::Write => write_symbol(f, *s),
+        Value::Symbol(s) => write!(f, "{}", s),
+        Value::Bool(true) => write!(f, "#t"),
+        Value::Bool(false) => write!(f, "#f"),
+        Value::Char(c) if style == Style::Write => write_char(f, *c),
+        Value::Char(c) => write!(f, "{}", c),
+        Value::Nil => write!(f, "nil"),
+    }
+}
+
+#[cfg(test)]
+mod test_print {
+    use crate::eval::{list, Value, Vector};
+    use crate::number::Number;
+    use crate::parse;
+    use crate::print::{display, write};
+    use num_rational::BigRational;
+    use proptest::prelude::*;
+
+    #[test]
+    fn print_lists() {
+        let nums = |ns: &[i64]| ns.iter().copied().map(Value::int).collect::<Vec<_>>();
+        assert_eq!(
+            write(&list(nums(&[1, 2, 3]), Value::Nil)).to_string(),
+            "(1 2 3)"
+        );
+        assert_eq!(
+            write(&list(nums(&[1]), Value::int(2))).to_string(),
+            "(1 . 2)"
+        );
+        assert_eq!(
+            write(&list(
+                vec![list(nums(&[1]), Value::Nil), Value::Nil],
+                Value::Nil
+            ))
+            .to_string(),
+            "((1) nil)"
+        );
+        assert_eq!(
+            write(&parse::read("(quote (a `(b ,c ,@d)))").unwrap()).to_string(),
+            "'(a `(b ,c ,@d))"
+        );
+    }
+
+    #[test]
+    fn print_cycles() {
+        let xs = list(vec![Value::int(1), Value::int(2)], Value::Nil);
+        let cell = |value: &Value| match value {
+            Value::Cons(cons) => cons.clone(),
+            _ => unreachable!(),
+        };
+        let last = cell(&cell(&xs).cdr());
+        last.set_cdr(xs.clone());
+        assert_eq!(write(&xs).to_string(), "#0=(1 2 . #0#)");
+        last.set_cdr(Value::Nil);
+        last.set_car(xs.clone());
+        assert_eq!(write(&xs).to_string(), "#0=(1 #0#)");
+
+        let inner = list(vec![Value::Symbol("a".into())], Value::Nil);
+        let v = Vector::new(vec![inner.clone(), inner]);
+        let vector = Value::Vector(v.clone());
+        assert_eq!(write(&vector).to_string(), "#((a) (a))");
+        v.set(1, vector.clone());
+        assert_eq!(write(&vector).to_string(), "#0=#((a) #0#)");
+        let quoted = list(
+            vec![Value::Symbol("quote".into()), vector.clone()],
+            Value::Nil,
+        );
+        assert_eq!(
+            write(&list(vec![quoted.clone(), vector], Value::Nil)).to_string(),
+            "('#0=#((a) #0#) #0#)"
+        );
+        cell(&cell(&quoted).cdr()).set_car(quoted.clone());
+        assert_eq!(write(&quoted).to_string(), "#0='#0#");
+    }
+
+    #[test]
+    fn write_and_display_strings() {
+        let s = Value::Str("say \"hi\" \\ bye".into());
+        assert_eq!(write(&s).to_string(), r#""say \"hi\" \\ bye""#);
+        assert_eq!(
+            write(&Value::Str("a\tb\n\u{7}".into())).to_string(),
+            r#""a\tb\n\u{7}""#
+        );
+        assert_eq!(display(&s).to_string(), r#"say "hi" \ bye"#);
+        assert_eq!(
+            display(&list(vec![s], Value::Nil)).to_string(),
+            r#"(say "hi" \ bye)"#
+        );
+    }
+
+    #[test]
+    fn write_symbols() {
+        use crate::symbol::Symbol;
+        let sym = |name: &str| Value::Symbol(name.into());
+        for (name, written) in [
+            ("a", "a"),
+            ("a b", "|a b|"),
+            ("", "||"),
+            ("nil", "|nil|"),
+            (".", "|.|"),
+            ("1", "|1|"),
+            ("#t", "|#t|"),
+            ("a|b\\c", r"|a\|b\\c|"),
+        ] {
+            assert_eq!(write(&sym(name)).to_string(), written);
+            assert_eq!(parse::read(written), Ok(sym(name)));
+        }
+        assert_eq!(display(&sym("a b")).to_string(), "a b");
+        assert_eq!(
+            parse::read("(a |.| b)"),
+            Ok(list(vec![sym("a"), sym("."), sym("b")], Value::Nil))
+        );
+        assert!(parse::read("|a").is_err());
+
+        let gensym = Symbol::gensym();
+        let written = write(&Value::Symbol(gensym)).to_string();
+        assert_eq!(written, format!("#:{}", gensym));
+        match parse::read(&written) {
+            Ok(Value::Symbol(s)) => {
+                assert_eq!(s.as_str(), gensym.as_str());
+                assert!(!s.is_interned() && s != gensym);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn datum() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Nil),
+            any::<bool>().prop_map(Value::Bool),
+            any::<char>().prop_map(Value::Char),
+            any::<i64>().prop_map(Value::int),
+            (any::<i64>(), 1..i64::MAX).prop_map(|(n, d)| {
+                Value::Number(Number::from(BigRational::new(n.into(), d.into())))
+            }),
+            any::<f64>()
+                .prop_filter("NaN is not equal to itself", |x| !x.is_nan())
+                .prop_map(|x| Value::Number(Number::Float(x))),
+            "[a-z][a-z0-9!?*<>=+-]*".prop_map(|s| Value::Symbol(s.as_str().into())),
+            any::<String>().prop_map(|s| Value::Symbol(s.as_str().into())),
+            any::<String>().prop_map(Value::Str),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                (prop::collection::vec(inner.clone(), 1..8), inner.clone())
+                    .prop_map(|(items, tail)| list(items, tail)),
+                prop::collection::vec(inner, 0..8)
+                    .prop_map(|items| Value::Vector(Vector::new(items))),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn written_data_reads_back(value in datum()) {
+            prop_assert_eq!(parse::read(&write(&value).to_string()), Ok(value));
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use super::ast::{Expr, Template, Token, TokenKind};
+use super::env::Env;
+use super::eval::*;
+use super::stack;
+
+/// The data `expr` was read from, e.g. the arguments of a macro call.
+pub fn quote(expr: &Expr) -> EvalResult {
+    stack::grow(|| datum(expr))
+}
+
+/// The value of `template`, where what is unquoted is evaluated in `env`
+/// and what is `unquote-splicing`d is spliced into its list.
+pub fn quasiquote(template: &Template, env: &Env) -> EvalResult {
+    stack::grow(|| {
+        Ok(match template {
+            Template::Datum(value) => value.clone(),
+            Template::Unquote(expr) => eval_with_env(expr, env)?,
+            Template::UnquoteSplicing(_) => {
+                return Err(EvalError::new("unquote-splicing outside of a list"))
+            }
+            Template::List(items, tail) => list(splice(items, env)?, quasiquote(tail, env)?),
+            Template::Vector(items) => Value::Vector(Vector::new(splice(items, env)?)),
+        })
+    })
+}
+
+/// The values of `templates`, with `unquote-splicing`s spliced in.
+fn splice(templates: &[Template], env: &Env) -> Result<Vec<Value>, EvalError> {
+    let mut items = Vec::new();
+    for template in templates {
+        match template {
+            Template::UnquoteSplicing(expr) => {
+                let spliced = eval_with_env(expr, env)?;
+                items.extend(list_to_vec(spliced, "unquote-splicing", 1)?);
+            }
+            template => items.push(quasiquote(template, env)?),
+        }
+    }
+    Ok(items)
+}
+
+fn datum(expr: &Expr) -> EvalResult {
+    use Expr::*;
+    Ok(match expr {
+        Symbol(_, s) => Value::Symbol(*s),
+        Str(_, s) => Value::Str(s.clone()),
+        Bool(_, b) => Value::Bool(*b),
+        Char(_, c) => Value::Char(*c),
+        Number(_, n) => Value::Number(n.clone()),
+        Nil(..) => Value::Nil,
+        Vector(_, vector) => Value::Vector(vector.clone()),
+        Quote(_, datum) => list(
+            vec![Value::Symbol("quote".into()), datum.clone()],
+            Value::Nil,
+        ),
+        Quasiquote(_, template) => list(
+            vec![
+                Value::Symbol("quasiquote".into()),
+                template_datum(template)?,
+            ],
+            Value::Nil,
+        ),
+        Unquote(tok, expr) | UnquoteSplicing(tok, expr) => {
+            datum_list(vec![keyword(tok)], vec![&**expr])?
+        }
+        Begin(_, tok, body, _) => {
+            datum_list(vec![keyword(tok)], body.iter().map(|e| &**e).collect())?
+        }
+        If(_, tok, cond, true_then, false_then, _) => datum_list(
+            vec![keyword(tok)],
+            vec![&**cond, &**true_then, &**false_then],
+        )?,
+        Define(_, tok, sym, value, _) => {
+            datum_list(vec![keyword(tok), keyword(sym)], vec![&**value])?
+        }
+        Lambda(_, tok, params, body, _) => datum_list(
+            vec![keyword(tok), symbols(params)],
+            body.iter().map(|e| &**e).collect(),
+        )?,
+        Defun(_, tok, name, params, body, _) | Defmacro(_, tok, name, params, body, _) => {
+            datum_list(
+                vec![keyword(tok), keyword(name), symbols(params)],
+                body.iter().map(|e| &**e).collect(),
+            )?
+        }
+        Let(_, tok, bindings, body, _)
+        | LetStar(_, tok, bindings, body, _)
+        | Letrec(_, tok, bindings, body, _) => {
+            let bindings = bindings
+                .iter()
+                .map(|(sym, value)| datum_list(vec![keyword(sym)], vec![value]))
+                .collect::<Result<Vec<_>, _>>()?;
+            datum_list(
+                vec![keyword(tok), list(bindings, Value::Nil)],
+                body.iter().map(|e| &**e).collect(),
+            )?
+        }
+        Call(_, head, args, ..) => {
+            let mut exprs = vec![&**head];
+            exprs.extend(args);
+            datum_list(Vec::new(), exprs)?
+        }
+    })
+}
+
+/// The data `template` was read from.
+fn template_datum(template: &Template) -> EvalResult {
+    let unquoted = |name: &str, expr| {
+        Ok(list(
+            vec![Value::Symbol(name.into()), quote(expr)?],
+            Value::Nil,
+        ))
+    };
+    stack::grow(|| match template {
+        Template::Datum(value) => Ok(value.clone()),
+        Template::Unquote(expr) => unquoted("unquote", expr),
+        Template::UnquoteSplicing(expr) => unquoted("unquote-splicing", expr),
+        Template::List(items, tail) => Ok(list(
+            items.iter().map(template_datum).collect::<Result<_, _>>()?,
+            template_datum(tail)?,
+        )),
+        Template::Vector(items) => Ok(Value::Vector(Vector::new(
+            items.iter().map(template_datum).collect::<Result<_, _>>()?,
+        ))),
+    })
+}
+
+/// A list of `items` followed by the data of `exprs`, where `(a . b)` makes
+/// a dotted pair. As in `parse::read`, a `.` must come after at least one
+/// item and before exactly one.
+fn datum_list(items: Vec<Value>, mut exprs: Vec<&Expr>) -> EvalResult {
+    let dot = exprs
+        .iter()
+        .position(|expr| matches!(expr, Expr::Symbol(_, s) if s == "."));
+    let tail = match dot {
+        None => None,
+        Some(i) if i + 2 == exprs.len() && (i > 0 || !items.is_empty()) => exprs.pop(),
+        Some(_) => return Err(EvalError::new("unexpected `.`")),
+    };
+    exprs.truncate(dot.unwrap_or(exprs.len()));
+    let mut items = items;
+    for expr in exprs {
+        items.push(quote(expr)?);
+    }
+    let tail = match tail {
+        Some(expr) => quote(expr)?,
+        None => Value::Nil,
+    };
+    Ok(list(items, tail))
+}
+
+fn keyword(token: &Token) -> Value {
+    Value::Symbol(match &token.kind {
+        TokenKind::Symbol(s) => *s,
+        TokenKind::Unquote => "unquote".into(),
+        TokenKind::UnquoteSplicing => "unquote-splicing".into(),
+        other => format!("{:?}", other).as_str().into(),
+    })
+}
+
+fn symbols(tokens: &[Token]) -> Value {
+    list(tokens.iter().map(keyword).collect(), Value::Nil)
+}
+
+#[cfg(test)]
+mod test_quote {
+    use crate::eval::{eval_with_env, list, ErrorKind, Value};
+    use crate::{env, parse};
+
+    fn eval(src: &str) -> Value {
+        let env = env::make_global_env();
+        let mut ret = Value::Nil;
+        for expr in parse::parse_program(src).unwrap() {
+            ret = eval_with_env(&expr, &env).unwrap();
+        }
+        ret
+    }
+
+    fn sym(s: &str) -> Value {
+        Value::Symbol(s.into())
+    }
+
+    #[test]
+    fn quote_data() {
+        assert_eq!(eval("'a"), sym("a"));
+        assert_eq!(eval("'()"), Value::Nil);
+        assert_eq!(
+            eval("'(1 \"two\" (three))"),
+            list(
+                vec![
+                    Value::int(1),
+                    Value::Str("two".into()),
+                    list(vec![sym("three")], Value::Nil)
+                ],
+                Value::Nil
+            )
+        );
+        assert_eq!(
+            eval("(quote (1 . 2))"),
+            list(vec![Value::int(1)], Value::int(2))
+        );
+        assert_eq!(eval("`(1 . ,(+ 1 1))"), eval("'(1 . 2)"));
+        for src in ["'(1 . 2 3)", "'(. 2)", "'(1 . 2 . 3)", "'(1 .)", "`(1 .)"] {
+            assert!(parse::parse(src).is_err(), "{}", src);
+        }
+        assert_eq!(
+            eval("''(if a b c)"),
+            list(
+                vec![
+                    sym("quote"),
+                    list(vec![sym("if"), sym("a"), sym("b"), sym("c")], Value::Nil)
+                ],
+                Value::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn quote_special_form_shapes() {
+        let shapes = [
+            "(if a)",
+            "(let x)",
+            "(let* ((a)) . b)",
+            "(letrec)",
+            "(lambda)",
+            "(setq 1 2)",
+            "(defun)",
+            "(defmacro m)",
+            "(begin . 1)",
+            "(quote)",
+            "(quasiquote a b)",
+            "(unquote)",
+        ];
+        for shape in &shapes {
+            let quoted = eval(&format!("'{}", shape));
+            assert_eq!(Ok(quoted.clone()), parse::read(shape), "{}", shape);
+            assert_eq!(eval(&format!("(quote {})", shape)), quoted, "{}", shape);
+        }
+        assert_eq!(eval("(car '((if) 1))"), list(vec![sym("if")], Value::Nil));
+    }
+
+    #[test]
+    fn quasiquote_data() {
+        assert_eq!(
+            eval("(setq x 2) (setq xs '(3 4)) `(1 ,x ,@xs (nested ,(+ x 1)))"),
+            eval("'(1 2 3 4 (nested 3))")
+        );
+        assert_eq!(eval("`(a `(b ,(c ,(+ 1 2))))"), eval("'(a `(b ,(c 3)))"));
+        assert_eq!(
+            eval("(setq x 2) `(a (quasiquote (b (unquote (c (unquote x))))))"),
+            eval("'(a `(b ,(c 2)))")
+        );
+        assert_eq!(eval("(setq x 2) `#(1 ,x ,@'(3 4))"), eval("#(1 2 3 4)"));
+        assert_eq!(eval("`(1 (unquote (+ 1 1)))"), eval("'(1 2)"));
+    }
+
+    #[test]
+    fn quasiquote_special_form_shapes() {
+        assert_eq!(
+            eval("(setq b '(x 1)) (setq body '((f x) x)) `(let (,b) ,@body)"),
+            eval("'(let ((x 1)) (f x) x)")
+        );
+        assert_eq!(
+            eval("(setq ps '(a b)) `(lambda ,ps (if) ,@ps)"),
+            eval("'(lambda (a b) (if) a b)")
+        );
+        assert_eq!(
+            eval("(setq s 'x) `(setq ,s ,(+ 1 2) extra)"),
+            eval("'(setq x 3 extra)")
+        );
+        assert_eq!(eval("`(quote)"), eval("'(quote)"));
+        assert_eq!(
+            eval("(setq x 1) `(1 . ,x)"),
+            list(vec![Value::int(1)], Value::int(1))
+        );
+        let expr = parse::parse("`(1 . ,@'(2))").unwrap();
+        let err = eval_with_env(&expr, &env::make_global_env()).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::Message("unquote-splicing outside of a list".into())
+        );
+    }
+}
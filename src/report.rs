@@ -0,0 +1,36 @@
+use codespan::Span;
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::files::{Files, SimpleFile};
+use codespan_reporting::term::{self, termcolor::NoColor};
+use std::ops::Range;
+
+// token spans are 1-based, codespan-reporting wants byte offsets
+fn range(source: &str, span: Span) -> Range<usize> {
+    let start = (span.start().to_usize().max(1) - 1).min(source.len());
+    let end = (span.end().to_usize().max(1) - 1).clamp(start, source.len());
+    start..end
+}
+
+/// One-based line and column where `span` starts in `source`.
+pub fn location(source: &str, span: Span) -> (usize, usize) {
+    let file = SimpleFile::new("", source);
+    let location = file
+        .location((), range(source, span).start)
+        .expect("span out of source");
+    (location.line_number, location.column_number)
+}
+
+/// Render `message` as a diagnostic with the snippet of `source` under `span`
+/// marked by carets, followed by `notes`.
+pub fn render(name: &str, source: &str, message: &str, span: Span, notes: Vec<String>) -> String {
+    let file = SimpleFile::new(name, source);
+    let diagnostic = Diagnostic::error()
+        .with_message(message)
+        .with_labels(vec![Label::primary((), range(source, span))])
+        .with_notes(notes);
+
+    let mut out = NoColor::new(Vec::new());
+    term::emit(&mut out, &term::Config::default(), &file, &diagnostic)
+        .expect("Can't render diagnostic");
+    String::from_utf8_lossy(&out.into_inner()).into_owned()
+}
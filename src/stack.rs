@@ -0,0 +1,19 @@
+//! Deep recursion without overflowing the native stack.
+//!
+//! The evaluator, the reader and the printer recurse once per level of
+//! nesting of the code or data they walk. Rather than trusting the thread
+//! they run on to have enough stack, each level checks how much is left and
+//! continues on a new segment allocated on the heap when it runs low, so the
+//! only limits on nesting are the explicit ones, e.g. `eval::MAX_DEPTH`.
+
+/// Stack that must be left for a level to run on the current segment: the
+/// largest frames, of a debug build, take some 25KB per level.
+const RED_ZONE: usize = 256 * 1024;
+
+/// Size of each new segment.
+const SEGMENT: usize = 4 * 1024 * 1024;
+
+/// Run `f`, on a new stack segment if the current one is nearly used up.
+pub fn grow<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, SEGMENT, f)
+}
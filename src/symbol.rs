@@ -0,0 +1,123 @@
+//! Interned symbols.
+//!
+//! A `Symbol` is an index into a per-thread table of names, so comparing and
+//! hashing one, e.g. to look a variable up, doesn't touch the name at all.
+//! Names are never freed: a program only ever uses so many symbols.
+//!
+//! As the table is per thread, a symbol is neither `Send` nor `Sync`: on
+//! another thread its index would name something else.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32, PhantomData<*const ()>);
+
+struct Interner {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, Symbol>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner {
+        names: Vec::new(),
+        ids: HashMap::new(),
+    });
+}
+
+impl Interner {
+    fn push(&mut self, name: &str) -> Symbol {
+        let name: &'static str = Box::leak(name.into());
+        self.names.push(name);
+        Symbol(self.names.len() as u32 - 1, PhantomData)
+    }
+}
+
+impl Symbol {
+    /// The symbol named `name`, the same one every time.
+    pub fn intern(name: &str) -> Symbol {
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            if let Some(&symbol) = interner.ids.get(name) {
+                return symbol;
+            }
+            let symbol = interner.push(name);
+            let name = interner.names[symbol.0 as usize];
+            interner.ids.insert(name, symbol);
+            symbol
+        })
+    }
+
+    /// A new symbol that is not interned, so it differs from every other
+    /// symbol, including ones with the same name.
+    pub fn gensym() -> Symbol {
+        let name = INTERNER.with(|interner| format!("g{}", interner.borrow().names.len()));
+        Symbol::uninterned(&name)
+    }
+
+    /// A new symbol named `name` that is not interned, like a `gensym`.
+    pub fn uninterned(name: &str) -> Symbol {
+        INTERNER.with(|interner| interner.borrow_mut().push(name))
+    }
+
+    /// Whether `self` is the symbol `intern` returns for its name, i.e. not
+    /// a `gensym`.
+    pub fn is_interned(self) -> bool {
+        INTERNER.with(|interner| {
+            let interner = interner.borrow();
+            interner.ids.get(interner.names[self.0 as usize]) == Some(&self)
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().names[self.0 as usize])
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test_symbol {
+    use crate::symbol::Symbol;
+
+    #[test]
+    fn intern_and_gensym() {
+        assert_eq!(Symbol::intern("foo"), Symbol::intern("foo"));
+        assert_ne!(Symbol::intern("foo"), Symbol::intern("bar"));
+        assert_eq!(Symbol::intern("foo").as_str(), "foo");
+
+        let g = Symbol::gensym();
+        assert_ne!(g, Symbol::gensym());
+        assert_ne!(g, Symbol::intern(g.as_str()));
+    }
+}